@@ -0,0 +1,163 @@
+use std::fmt::{self, Display, Formatter};
+
+/// Reason a block was rejected by `BlockChain::add_block`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    /// The parent is not in the block tree; the block may be retried once it is.
+    UnknownParent,
+    /// The block links to another parent than the block it is checked against, as
+    /// `BlockChain::verify_integrity` finds when the height index skips a branch.
+    PrevHashMismatch,
+    /// The timestamp regresses to or before the median time past.
+    TimeTooOld {
+        median_time_past: i64,
+        timestamp: i64,
+    },
+    TimeTooNew {
+        max: i64,
+        timestamp: i64,
+    },
+    MerkleMismatch,
+    InsufficientWork,
+    UnexpectedBits {
+        expected: u32,
+        found: u32,
+    },
+    BadProposerSignature,
+    InsufficientStake,
+    InactiveValidator,
+    UnexpectedProposer,
+    SlotNotAfterParent {
+        slot: u64,
+        parent: u64,
+    },
+    UnexpectedSlot {
+        expected: u64,
+        found: u64,
+    },
+    /// The timestamp falls outside the slot the block claims.
+    SlotTimeMismatch {
+        slot: u64,
+        timestamp: i64,
+    },
+    /// The slot has not begun by local time.
+    SlotInFuture {
+        slot: u64,
+        current: u64,
+    },
+    BadVrfProof,
+    InvalidTransaction {
+        index: usize,
+    },
+    MisplacedCoinbase {
+        index: usize,
+    },
+    ExcessiveCoinbase {
+        claimed: u64,
+        allowed: u64,
+    },
+    /// The fees, with the subsidy, add up past `u64::MAX`.
+    FeeOverflow,
+    ImmatureCoinbaseSpend {
+        index: usize,
+    },
+    InsufficientBalance {
+        index: usize,
+    },
+    InsufficientBond {
+        index: usize,
+    },
+    InvalidEvidence {
+        index: usize,
+    },
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::UnknownParent => write!(f, "parent block is not known"),
+            Self::PrevHashMismatch => write!(f, "previous hash does not match parent block"),
+            Self::TimeTooOld {
+                median_time_past,
+                timestamp,
+            } => {
+                write!(
+                    f,
+                    "timestamp {} is not after median time past {}",
+                    timestamp, median_time_past
+                )
+            }
+            Self::TimeTooNew { max, timestamp } => {
+                write!(
+                    f,
+                    "timestamp {} is more than the allowed drift ahead, limit {}",
+                    timestamp, max
+                )
+            }
+            Self::MerkleMismatch => write!(f, "merkle root does not match transactions"),
+            Self::InsufficientWork => write!(f, "block hash does not meet the target"),
+            Self::UnexpectedBits { expected, found } => {
+                write!(
+                    f,
+                    "bits {:#010x} differ from required {:#010x}",
+                    found, expected
+                )
+            }
+            Self::BadProposerSignature => write!(f, "proposer signature is invalid"),
+            Self::InsufficientStake => write!(f, "proposer does not hold the minimum stake"),
+            Self::InactiveValidator => write!(f, "proposer is not in the active validator set"),
+            Self::UnexpectedProposer => write!(f, "proposer was not selected for this height"),
+            Self::SlotNotAfterParent { slot, parent } => {
+                write!(f, "slot {} is not after parent slot {}", slot, parent)
+            }
+            Self::UnexpectedSlot { expected, found } => {
+                write!(f, "slot {} differs from height {}", found, expected)
+            }
+            Self::SlotTimeMismatch { slot, timestamp } => {
+                write!(f, "timestamp {} lies outside slot {}", timestamp, slot)
+            }
+            Self::SlotInFuture { slot, current } => {
+                write!(f, "slot {} is ahead of the current slot {}", slot, current)
+            }
+            Self::BadVrfProof => write!(f, "VRF proof or output is invalid"),
+            Self::InvalidTransaction { index } => write!(f, "transaction {} is invalid", index),
+            Self::MisplacedCoinbase { index } => {
+                write!(f, "coinbase at position {} is not first", index)
+            }
+            Self::ExcessiveCoinbase { claimed, allowed } => {
+                write!(
+                    f,
+                    "coinbase claims {} but only {} is allowed",
+                    claimed, allowed
+                )
+            }
+            Self::FeeOverflow => write!(f, "transaction fees overflow the block reward"),
+            Self::ImmatureCoinbaseSpend { index } => {
+                write!(f, "transaction {} spends an immature coinbase", index)
+            }
+            Self::InsufficientBalance { index } => {
+                write!(
+                    f,
+                    "transaction {} bonds more than the signer's balance",
+                    index
+                )
+            }
+            Self::InsufficientBond { index } => {
+                write!(
+                    f,
+                    "transaction {} unbonds more than the signer's stake",
+                    index
+                )
+            }
+            Self::InvalidEvidence { index } => {
+                write!(
+                    f,
+                    "transaction {} does not prove an unpunished double sign",
+                    index
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
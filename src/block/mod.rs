@@ -1,14 +1,22 @@
+pub mod error;
 pub mod pos;
 pub mod pow;
+pub mod pow_hash;
+pub mod retarget;
+pub mod vrf;
 use std::fmt::{self, Display, Formatter};
 
 use anyhow::Result;
 use log::debug;
+use num_bigint::BigUint;
 use rs_merkle::{MerkleTree, algorithms::Sha256 as MerkleSha256};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
-use crate::hash::Hashable;
+use crate::{chain::blockchain_control, hash::Hashable};
+
+use error::ValidationError;
+use pos::StakeOp;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Block<T: Transaction, H: Consensus> {
@@ -26,8 +34,34 @@ pub struct BlockHeader<D> {
 
 pub trait Consensus: Serialize + Clone + Default {
     type Data: Clone + Serialize + for<'a> Deserialize<'a> + Display;
-    fn validate<T: Transaction>(&self, block: &Block<T, Self>) -> bool;
+    /// Identifies the consensus in exported archives.
+    const NAME: &'static str;
+    fn validate<T: Transaction>(&self, block: &Block<T, Self>) -> Result<(), ValidationError>;
+    /// Rules against local time, `now` in unix seconds. Checked only when a block is
+    /// received, as a block early today is valid tomorrow.
+    fn validate_time(
+        &self,
+        _header: &BlockHeader<Self::Data>,
+        _now: i64,
+    ) -> Result<(), ValidationError> {
+        Ok(())
+    }
+    /// Weight a block adds to its branch, used to pick the heaviest chain.
+    fn block_weight(&self, header: &BlockHeader<Self::Data>) -> BigUint;
+    /// Advances the state past an accepted block at `height`. Called on a copy of the
+    /// parent's state, and once on the default state for genesis.
+    fn apply_block<T: Transaction>(&mut self, _height: u64, _block: &Block<T, Self>) {}
+    /// Blocks that must follow a coinbase before its outputs may be spent; 0 disables
+    /// the rule.
+    fn coinbase_maturity(&self) -> u64 {
+        0
+    }
     fn genesis_data() -> Self::Data;
+    /// Decodes the state stored by the flat key layout into a state before genesis
+    /// with the same configuration, `None` if `raw` does not decode.
+    fn from_legacy_state(_raw: &[u8]) -> Option<Self> {
+        None
+    }
     fn generate_block<T: Transaction>(
         &self,
         prev: &Block<T, Self>,
@@ -39,6 +73,27 @@ pub trait Transaction: Hashable + Serialize {
     fn verify(&self) -> bool {
         false
     }
+
+    /// Amount minted if this is a coinbase. Only the first transaction of a block may
+    /// be one.
+    fn coinbase_amount(&self) -> Option<u64> {
+        None
+    }
+
+    /// Fee left to the block producer.
+    fn fee(&self) -> u64 {
+        0
+    }
+
+    /// Hashes of the transactions whose outputs this one spends.
+    fn spent_txids(&self) -> Vec<[u8; 32]> {
+        Vec::new()
+    }
+
+    /// Stake change this transaction makes, applied by proof of stake consensus.
+    fn stake_op(&self) -> Option<StakeOp> {
+        None
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
@@ -62,6 +117,18 @@ impl Transaction for DummyTransaction {}
 pub struct Transactions<T: Transaction>(pub Vec<T>);
 
 impl<T: Transaction, H: Consensus> Block<T, H> {
+    pub fn from_parts(header: BlockHeader<H::Data>, txs: Transactions<T>) -> Self {
+        Block { header, txs }
+    }
+
+    pub fn transactions(&self) -> &Transactions<T> {
+        &self.txs
+    }
+
+    pub fn into_parts(self) -> (BlockHeader<H::Data>, Transactions<T>) {
+        (self.header, self.txs)
+    }
+
     pub fn merkle_root(&self) -> Option<Vec<u8>> {
         self.txs.merkle_root()
     }
@@ -84,19 +151,59 @@ impl<T: Transaction, H: Consensus> Block<T, H> {
     //     Ok(block)
     // }
 
-    pub fn validate(&self, prev: &Block<T, H>) -> bool {
+    /// Structural checks against the parent block: linkage, merkle root and coinbase
+    /// position.
+    ///
+    /// Passing does not make a block acceptable. The timestamp rules need the median
+    /// time past of the branch and local time, and the consensus and transaction rules
+    /// need the parent's state, so only `BlockChain::add_block` applies them all;
+    /// blocks from peers or archives must go through it.
+    pub fn validate(&self, prev: &Block<T, H>) -> Result<(), ValidationError> {
         let prev_valid = self.header.prev_hash == prev.header.hash();
         debug!("prev_valid: {}", prev_valid);
-        let time_valid = self.header.timestamp >= prev.header.timestamp;
-        debug!("time_valid: {}", time_valid);
-        let merkle_valid = {
-            let Some(calc) = self.merkle_root() else {
-                return false;
-            };
-            calc == self.header.merkle_root
-        };
+        if !prev_valid {
+            return Err(ValidationError::PrevHashMismatch);
+        }
+
+        match self.merkle_root() {
+            Some(calc) if calc == self.header.merkle_root => {}
+            _ => return Err(ValidationError::MerkleMismatch),
+        }
+
+        match self
+            .txs
+            .0
+            .iter()
+            .skip(1)
+            .position(|tx| tx.coinbase_amount().is_some())
+        {
+            Some(i) => Err(ValidationError::MisplacedCoinbase { index: i + 1 }),
+            None => Ok(()),
+        }
+    }
 
-        prev_valid && time_valid && merkle_valid
+    pub fn coinbase(&self) -> Option<&T> {
+        self.txs
+            .0
+            .first()
+            .filter(|tx| tx.coinbase_amount().is_some())
+    }
+
+    /// Sum of `Transaction::fee` over the non-coinbase transactions, `None` if it
+    /// overflows.
+    pub fn fees(&self) -> Option<u64> {
+        self.txs
+            .0
+            .iter()
+            .filter(|tx| tx.coinbase_amount().is_none())
+            .try_fold(0u64, |sum, tx| sum.checked_add(tx.fee()))
+    }
+
+    pub fn verify_transactions(&self) -> Result<(), ValidationError> {
+        match self.txs.0.iter().position(|tx| !tx.verify()) {
+            Some(index) => Err(ValidationError::InvalidTransaction { index }),
+            None => Ok(()),
+        }
     }
 
     pub fn genesis<TD: Transaction + Default>() -> Block<TD, H> {
@@ -104,7 +211,7 @@ impl<T: Transaction, H: Consensus> Block<T, H> {
             header: BlockHeader {
                 prev_hash: "0".repeat(64).as_bytes().to_vec(),
                 merkle_root: "0".repeat(64).as_bytes().to_vec(),
-                timestamp: 1685000000,
+                timestamp: blockchain_control::GENESIS_TIMESTAMP,
                 data: H::genesis_data(),
             },
             txs: Transactions::<TD>::test_new(),
@@ -112,14 +219,24 @@ impl<T: Transaction, H: Consensus> Block<T, H> {
     }
 }
 
+impl<D: Serialize> BlockHeader<D> {
+    /// Bytes the block id and proof-of-work hashes are computed over.
+    pub fn preimage(&self) -> Vec<u8> {
+        let data = bincode::serialize(&self.data).unwrap();
+        [
+            self.prev_hash.as_slice(),
+            &self.merkle_root,
+            &self.timestamp.to_le_bytes(),
+            &data,
+        ]
+        .concat()
+    }
+}
+
 impl<D: Serialize + Clone> Hashable for BlockHeader<D> {
     fn hash(&self) -> [u8; 32] {
         let mut hasher = Sha256::new();
-        hasher.update(self.prev_hash.clone());
-        hasher.update(self.merkle_root.clone());
-        hasher.update(self.timestamp.to_le_bytes());
-        let val = bincode::serialize(&self.data).unwrap();
-        hasher.update(val);
+        hasher.update(self.preimage());
         let result = hasher.finalize();
 
         let mut hasher = Sha256::new();
@@ -1,15 +1,23 @@
-use std::{collections::HashMap, fmt::Display};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::Display,
+};
 
 use anyhow::{Result, bail};
 use chrono::Utc;
 use ed25519_dalek::{SecretKey, Signature, Signer, SigningKey, Verifier, VerifyingKey};
-use rand::Rng;
+use num_bigint::BigUint;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
-use crate::{block::Transaction, hash::Hashable};
+use crate::{block::Transaction, chain::blockchain_control, hash::Hashable};
 
-use super::{Block, BlockHeader, Consensus, Transactions};
+use super::{Block, BlockHeader, Consensus, Transactions, error::ValidationError, vrf};
+
+/// Slots `generate_block` searches for a local leader under `Election::Vrf`.
+const MAX_SLOT_SEARCH: u64 = 10_000;
+/// Basis points in one.
+const BPS: u128 = 10_000;
 
 pub trait TransactionSign: Transaction {
     fn signer(&self) -> &str;
@@ -18,8 +26,86 @@ pub trait TransactionSign: Transaction {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum TransactionType {
-    Transfer { to: String, amount: u64 },
-    Stake { amount: u64 },
+    Transfer {
+        to: String,
+        amount: u64,
+    },
+    Stake {
+        amount: u64,
+    },
+    /// Starts unbonding `amount` of the signer's stake, see `PoS::stake_lock_period`.
+    Unstake {
+        amount: u64,
+    },
+    /// Reports a validator that signed two blocks for one slot or height, see
+    /// `PoS::slash`.
+    Evidence(Box<DoubleSignEvidence>),
+}
+
+/// Two conflicting headers signed by the same proposer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DoubleSignEvidence {
+    pub first: BlockHeader<PoSData>,
+    pub second: BlockHeader<PoSData>,
+}
+
+impl DoubleSignEvidence {
+    /// Validator proven to have double signed: both headers name it as proposer of the
+    /// same slot or on top of the same parent, differ in what they sign and carry
+    /// valid signatures of its key. A shared parent fixes the height whatever slot
+    /// the headers claim, so one proposer forking its own parent conflicts under
+    /// either election.
+    pub fn offender(&self) -> Option<VerifyingKey> {
+        let (first, second) = (&self.first, &self.second);
+        let key = first.data.validator_key;
+        let signed = |header: &BlockHeader<PoSData>| {
+            key.verify(&header.signing_hash(), &header.data.signature)
+                .is_ok()
+        };
+        let conflicting = second.data.validator_key == key
+            && (first.data.slot == second.data.slot || first.prev_hash == second.prev_hash)
+            && first.signing_hash() != second.signing_hash();
+        (conflicting && signed(first) && signed(second)).then_some(key)
+    }
+
+    /// Slot the double sign is punished for: the lower one the headers claim, so the
+    /// same pair reported in either order is one offense.
+    pub fn slot(&self) -> u64 {
+        self.first.data.slot.min(self.second.data.slot)
+    }
+}
+
+/// Stake change a transaction asks of its signer's account.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StakeOp {
+    /// Moves `amount` from the staker's balance to its stake.
+    Bond {
+        staker: VerifyingKey,
+        amount: u64,
+        sequence: u64,
+    },
+    /// Moves `amount` of the staker's stake to the unbonding queue, dropping the staker
+    /// from the active set if less than `min_stake_amount` stays bonded.
+    Unbond {
+        staker: VerifyingKey,
+        amount: u64,
+        sequence: u64,
+    },
+    /// Slashes the validator evidence proves double signed at `slot`, `None` if it
+    /// proves nothing, paying part of the penalty to `reporter`.
+    Slash {
+        reporter: VerifyingKey,
+        offender: Option<VerifyingKey>,
+        slot: u64,
+    },
+}
+
+/// Unbonded stake credited back to `staker` once the chain reaches `release_height`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Unbonding {
+    pub staker: VerifyingKey,
+    pub amount: u64,
+    pub release_height: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -27,6 +113,7 @@ pub struct PoSTransaction {
     pub tx_type: TransactionType,
     pub signer: String,
     pub signature: Vec<u8>,
+    /// For `Stake` and `Unstake`, the signer's `PoS::next_sequence`.
     pub sequence: u64,
 }
 
@@ -47,15 +134,67 @@ impl Default for PoSTransaction {
 impl Hashable for PoSTransaction {
     fn try_hash(&self) -> Option<[u8; 32]> {
         let mut hasher = Sha256::new();
-        let Some(val) = bincode::serialize(&self).ok() else {
-            return None;
-        };
+        let val = bincode::serialize(&self).ok()?;
         hasher.update(val);
         Some(hasher.finalize().into())
     }
 }
 
-impl Transaction for PoSTransaction {}
+impl PoSTransaction {
+    /// Hash covered by the signature: the transaction with an empty signature.
+    pub fn signing_hash(&self) -> [u8; 32] {
+        let mut unsigned = self.clone();
+        unsigned.signature.clear();
+        unsigned.hash()
+    }
+
+    /// Sets `signer` to the hex encoded public key and signs the transaction.
+    pub fn sign(&mut self, key: &SigningKey) {
+        self.signer = hex::encode(key.verifying_key().as_bytes());
+        self.signature = key.sign(&self.signing_hash()).to_vec();
+    }
+
+    /// Public key decoded from `signer`.
+    pub fn signer_key(&self) -> Option<VerifyingKey> {
+        hex::decode(&self.signer)
+            .ok()
+            .and_then(|bytes| VerifyingKey::try_from(bytes.as_slice()).ok())
+    }
+}
+
+impl Transaction for PoSTransaction {
+    fn verify(&self) -> bool {
+        let Some(signer) = self.signer_key() else {
+            return false;
+        };
+        let Ok(signature) = Signature::from_slice(&self.signature) else {
+            return false;
+        };
+        signer.verify(&self.signing_hash(), &signature).is_ok()
+    }
+
+    fn stake_op(&self) -> Option<StakeOp> {
+        let staker = self.signer_key()?;
+        match self.tx_type {
+            TransactionType::Transfer { .. } => None,
+            TransactionType::Stake { amount } => Some(StakeOp::Bond {
+                staker,
+                amount,
+                sequence: self.sequence,
+            }),
+            TransactionType::Unstake { amount } => Some(StakeOp::Unbond {
+                staker,
+                amount,
+                sequence: self.sequence,
+            }),
+            TransactionType::Evidence(ref evidence) => Some(StakeOp::Slash {
+                reporter: staker,
+                offender: evidence.offender(),
+                slot: evidence.slot(),
+            }),
+        }
+    }
+}
 
 impl TransactionSign for PoSTransaction {
     fn signature(&self) -> &[u8] {
@@ -67,36 +206,136 @@ impl TransactionSign for PoSTransaction {
     }
 }
 
+/// How the proposer of a block is chosen.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Election {
+    /// One public proposer per height, see `PoS::select_proposer`.
+    #[default]
+    Stake,
+    /// Private leader election: a validator leads a slot if its VRF output over the
+    /// epoch seed and slot falls under a threshold proportional to its stake in
+    /// `PoS::active_validators`. Slots nobody leads are skipped.
+    ///
+    /// Slots are periods of `PoS::slot_duration` seconds from `PoS::genesis_time`; a
+    /// block is timestamped within its slot and cannot claim one that has not begun,
+    /// so a validator cannot search ahead for slots it leads.
+    Vrf {
+        /// Expected share of slots with a leader, in basis points.
+        active_slots_bps: u64,
+    },
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PoS {
     pub min_stake_amount: u64,
     pub stake_lock_period: u64, // pledge blocks
-    pub annual_interest_rate: f64,
+    /// Yearly interest on active stake, in basis points, e.g. `500` for 5%.
+    pub annual_interest_rate: u64,
+    /// Blocks the annual interest is spread over.
+    pub blocks_per_year: u64,
+    /// Share of each block's interest paid to its proposer rather than pro rata to
+    /// the active set, in basis points.
+    pub proposer_share_bps: u64,
     pub validator_count: usize,
     pub epoch_length: u64,
+    /// Forfeited from the stake of a slashed validator on top of `slash_bps`.
     pub security_deposit: u64,
-
+    /// Share of stake, bonded or unbonding, a double signing validator loses, in basis
+    /// points.
+    pub slash_bps: u64,
+    /// Share of a slashed amount paid to the reporter of the evidence, in basis
+    /// points. The rest is burned.
+    pub reporter_reward_bps: u64,
+    /// Blocks a slashed validator is kept out of the validator set.
+    pub jail_period: u64,
+
+    /// Stake of every validator candidate.
     pub cur_validators: HashMap<VerifyingKey, u64>,
+    /// Validators of the current epoch and their stake when it began, ordered by
+    /// public key so every node walks them the same way.
+    ///
+    /// The `Election::Vrf` threshold weighs these stakes rather than `cur_validators`:
+    /// stake bonded or unbonded mid-epoch changes nobody's leadership until the next
+    /// epoch, when it could otherwise be moved around to win chosen slots.
+    pub active_validators: Vec<(VerifyingKey, u64)>,
+    /// Unbonded balance of every account, the funds `Stake` transactions bond.
+    pub balances: HashMap<VerifyingKey, u64>,
+    /// Stake waiting out `stake_lock_period`, oldest first.
+    pub unbonding: VecDeque<Unbonding>,
+    /// Total rewards paid to every validator, credited to its balance.
+    pub rewards: HashMap<VerifyingKey, u64>,
+    /// Fractions of a unit of reward accrued but not yet paid, see `issue_rewards`.
+    pub reward_dust: HashMap<VerifyingKey, u128>,
+    /// Jailed validators and the height their jail ends.
+    pub jailed: HashMap<VerifyingKey, u64>,
+    /// Validator and slot of every double sign already punished.
+    pub slashed: HashSet<(VerifyingKey, u64)>,
+    /// Sequence the next `Stake` or `Unstake` of every signer must carry, so a signed
+    /// transaction applies once.
+    pub sequences: HashMap<VerifyingKey, u64>,
     // Insecure! For demonstration only
     pub validator_keys: HashMap<VerifyingKey, SecretKey>,
+    /// Height of the last block applied to this state, `None` before genesis.
+    pub height: Option<u64>,
+    pub election: Election,
+    /// Start of slot 0 under `Election::Vrf`, in unix seconds.
+    pub genesis_time: i64,
+    /// Seconds per slot under `Election::Vrf`.
+    pub slot_duration: u64,
+    /// Slot of the last block applied to this state.
+    pub last_slot: u64,
+    /// VRF input prefix, renewed from the hash of the last block of every epoch.
+    pub epoch_seed: [u8; 32],
+}
+
+/// `PoS` as stored by the flat key layout.
+#[derive(Deserialize)]
+struct LegacyPoS {
+    min_stake_amount: u64,
+    stake_lock_period: u64,
+    annual_interest_rate: f64,
+    validator_count: usize,
+    epoch_length: u64,
+    security_deposit: u64,
+    cur_validators: HashMap<VerifyingKey, u64>,
+    validator_keys: HashMap<VerifyingKey, SecretKey>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PoSData {
     pub validator_key: VerifyingKey,
     pub signature: Signature,
+    /// Slot the block was proposed for; the height under `Election::Stake`.
+    pub slot: u64,
+    /// Proof of leadership under `Election::Vrf`.
+    pub vrf: Option<VrfData>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VrfData {
+    pub output: Vec<u8>,
+    pub proof: Vec<u8>,
 }
 
 impl Display for PoSData {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "PoS\n validator: {:?}\n signature: {:?}",
-            self.validator_key, self.signature,
+            "PoS\n validator: {:?}\n signature: {:?}\n slot: {}",
+            self.validator_key, self.signature, self.slot,
         )
     }
 }
 
+impl BlockHeader<PoSData> {
+    /// Hash signed by the proposer: the header with an empty signature.
+    pub fn signing_hash(&self) -> [u8; 32] {
+        let mut unsigned = self.clone();
+        unsigned.data.signature = Signature::from_bytes(&[0; 64]);
+        unsigned.hash()
+    }
+}
+
 impl Hashable for PoS {
     fn try_hash(&self) -> Option<[u8; 32]> {
         let mut hasher = Sha256::new();
@@ -111,12 +350,31 @@ impl Default for PoS {
         Self {
             min_stake_amount: 1000,
             stake_lock_period: 10000,
-            annual_interest_rate: 0.1,
+            annual_interest_rate: 1000,
+            blocks_per_year: 365 * 24 * 60 * 60 / blockchain_control::TARGET_TIME_SPAN,
+            proposer_share_bps: 1000,
             validator_count: 5,
             epoch_length: 100,
             security_deposit: 100,
+            slash_bps: 500,
+            reporter_reward_bps: 1000,
+            jail_period: 10_000,
             cur_validators: HashMap::new(),
+            active_validators: Vec::new(),
+            balances: HashMap::new(),
+            unbonding: VecDeque::new(),
+            rewards: HashMap::new(),
+            reward_dust: HashMap::new(),
+            jailed: HashMap::new(),
+            slashed: HashSet::new(),
+            sequences: HashMap::new(),
             validator_keys: HashMap::new(),
+            height: None,
+            election: Election::Stake,
+            genesis_time: blockchain_control::GENESIS_TIMESTAMP,
+            slot_duration: 1,
+            last_slot: 0,
+            epoch_seed: [0; 32],
         }
     }
 }
@@ -129,40 +387,462 @@ impl PoS {
         self.validator_keys.insert(public_key, secret_key);
     }
 
-    fn select_validator(&self) -> Option<VerifyingKey> {
-        let total_stake: u64 = self.cur_validators.values().sum();
-        if total_stake == 0 {
-            return None;
+    /// Adds `amount` to the unbonded balance of `key`, e.g. for genesis allocations.
+    pub fn credit(&mut self, key: VerifyingKey, amount: u64) {
+        let balance = self.balances.entry(key).or_default();
+        *balance = balance.saturating_add(amount);
+    }
+
+    pub fn balance_of(&self, key: &VerifyingKey) -> u64 {
+        self.balances.get(key).copied().unwrap_or(0)
+    }
+
+    /// Bonded stake of `key`, whether or not it is in the active set.
+    pub fn stake_of(&self, key: &VerifyingKey) -> u64 {
+        self.cur_validators.get(key).copied().unwrap_or(0)
+    }
+
+    /// Sequence the next stake change signed by `key` must carry.
+    pub fn next_sequence(&self, key: &VerifyingKey) -> u64 {
+        self.sequences.get(key).copied().unwrap_or(0)
+    }
+
+    /// Total rewards `key` has been paid.
+    pub fn rewards_of(&self, key: &VerifyingKey) -> u64 {
+        self.rewards.get(key).copied().unwrap_or(0)
+    }
+
+    /// Denominator of `reward_dust`. A block accrues stake times `annual_interest_rate`
+    /// times a share in basis points, so a year of blocks pays the interest once.
+    fn reward_unit(&self) -> u128 {
+        BPS * BPS * self.blocks_per_year.max(1) as u128
+    }
+
+    /// Pays one block of interest. Every active validator earns on the lower of its
+    /// stake when the epoch began and its stake now, so stake unbonded mid-epoch
+    /// stops earning, and `proposer` also earns `proposer_share_bps` of the block's
+    /// total. Shares are accrued exactly and paid in whole units once they add up to
+    /// one.
+    fn issue_rewards(&mut self, proposer: &VerifyingKey) {
+        let rate = self.annual_interest_rate as u128;
+        let share = (self.proposer_share_bps as u128).min(BPS);
+        let earning: Vec<_> = self
+            .active_validators
+            .iter()
+            .map(|&(key, stake)| (key, stake.min(self.stake_of(&key)) as u128))
+            .collect();
+        let total: u128 = earning.iter().map(|&(_, stake)| stake).sum();
+        let mut accruals: Vec<_> = earning
+            .into_iter()
+            .map(|(key, stake)| (key, stake * rate * (BPS - share)))
+            .collect();
+        if self.active_stake(proposer).is_some() {
+            accruals.push((*proposer, total * rate * share));
+        }
+
+        let unit = self.reward_unit();
+        for (key, amount) in accruals {
+            let dust = self.reward_dust.entry(key).or_default();
+            *dust += amount;
+            let paid = u64::try_from(*dust / unit).unwrap_or(u64::MAX);
+            *dust %= unit;
+            if paid > 0 {
+                self.credit(key, paid);
+                let rewards = self.rewards.entry(key).or_default();
+                *rewards = rewards.saturating_add(paid);
+            }
+        }
+        self.reward_dust.retain(|_, dust| *dust > 0);
+    }
+
+    /// Credits back every unbonding entry due at `height`.
+    fn release_unbonded(&mut self, height: u64) {
+        while self
+            .unbonding
+            .front()
+            .is_some_and(|entry| entry.release_height <= height)
+        {
+            let entry = self.unbonding.pop_front().unwrap();
+            self.credit(entry.staker, entry.amount);
+        }
+    }
+
+    /// Applies the stake change of transaction `index` in the block at `height`,
+    /// leaving the state untouched if the signer cannot cover it or its sequence is
+    /// not the next one.
+    fn apply_stake_op(
+        &mut self,
+        index: usize,
+        op: StakeOp,
+        height: u64,
+    ) -> Result<(), ValidationError> {
+        let sequenced = match op {
+            StakeOp::Bond {
+                staker, sequence, ..
+            }
+            | StakeOp::Unbond {
+                staker, sequence, ..
+            } => Some((staker, sequence)),
+            StakeOp::Slash { .. } => None,
+        };
+        if let Some((staker, sequence)) = sequenced
+            && sequence != self.next_sequence(&staker)
+        {
+            return Err(ValidationError::InvalidTransaction { index });
         }
 
-        let mut rng = rand::rng();
-        let mut random = rng.random_range(0..total_stake);
+        match op {
+            StakeOp::Bond { staker, amount, .. } => {
+                let balance = self.balance_of(&staker);
+                if balance < amount {
+                    return Err(ValidationError::InsufficientBalance { index });
+                }
+                self.balances.insert(staker, balance - amount);
+                let stake = self.cur_validators.entry(staker).or_default();
+                *stake = stake.saturating_add(amount);
+            }
+            StakeOp::Unbond { staker, amount, .. } => {
+                let stake = self.stake_of(&staker);
+                if stake < amount {
+                    return Err(ValidationError::InsufficientBond { index });
+                }
+                if stake == amount {
+                    self.cur_validators.remove(&staker);
+                } else {
+                    self.cur_validators.insert(staker, stake - amount);
+                }
+                // Leaves the active set at once, as a slashed validator does, so it
+                // is never elected to propose a block `validate` would reject.
+                if stake - amount < self.min_stake_amount {
+                    self.active_validators.retain(|(key, _)| *key != staker);
+                }
+                self.unbonding.push_back(Unbonding {
+                    staker,
+                    amount,
+                    release_height: height.saturating_add(self.stake_lock_period),
+                });
+            }
+            StakeOp::Slash {
+                reporter,
+                offender,
+                slot,
+            } => {
+                let Some(offender) = offender else {
+                    return Err(ValidationError::InvalidEvidence { index });
+                };
+                if !self.slashed.insert((offender, slot)) {
+                    return Err(ValidationError::InvalidEvidence { index });
+                }
+                self.slash(&offender, &reporter, height);
+            }
+        }
+        if let Some((staker, sequence)) = sequenced {
+            self.sequences.insert(staker, sequence.saturating_add(1));
+        }
+        Ok(())
+    }
 
-        for (pub_key, &stake) in &self.cur_validators {
-            if random < stake {
-                return Some(*pub_key);
+    /// Punishes a double sign: takes `slash_bps` of the offender's stake and unbonding
+    /// entries plus `security_deposit` from its stake, pays `reporter_reward_bps` of
+    /// the total to `reporter` and jails the offender for `jail_period` blocks, out of
+    /// the active set immediately.
+    pub fn slash(&mut self, offender: &VerifyingKey, reporter: &VerifyingKey, height: u64) {
+        let slash_bps = (self.slash_bps as u128).min(BPS);
+        let cut = |amount: u64| (amount as u128 * slash_bps / BPS) as u64;
+
+        let stake = self.stake_of(offender);
+        let mut penalty = cut(stake).saturating_add(self.security_deposit).min(stake);
+        if stake == penalty {
+            self.cur_validators.remove(offender);
+        } else {
+            self.cur_validators.insert(*offender, stake - penalty);
+        }
+        for entry in self.unbonding.iter_mut() {
+            if entry.staker == *offender {
+                let amount = cut(entry.amount);
+                entry.amount -= amount;
+                penalty = penalty.saturating_add(amount);
             }
-            random -= stake;
         }
 
+        let reward = penalty as u128 * (self.reporter_reward_bps as u128).min(BPS) / BPS;
+        if reward > 0 {
+            self.credit(*reporter, reward as u64);
+        }
+        self.active_validators.retain(|(key, _)| key != offender);
+        self.jailed
+            .insert(*offender, height.saturating_add(self.jail_period));
+    }
+
+    pub fn is_jailed(&self, key: &VerifyingKey) -> bool {
+        self.jailed.contains_key(key)
+    }
+
+    /// Recomputes `active_validators` from `cur_validators`: the `validator_count`
+    /// largest stakes of at least `min_stake_amount`, ties going to the lower public
+    /// key. Runs after the last block of every epoch, ready for the first block of
+    /// the next.
+    pub fn rotate_validators(&mut self) {
+        let mut candidates: Vec<_> = self
+            .cur_validators
+            .iter()
+            .filter(|&(key, &stake)| stake >= self.min_stake_amount && !self.is_jailed(key))
+            .map(|(&key, &stake)| (key, stake))
+            .collect();
+        candidates.sort_by(|(a, a_stake), (b, b_stake)| {
+            b_stake
+                .cmp(a_stake)
+                .then_with(|| a.as_bytes().cmp(b.as_bytes()))
+        });
+        candidates.truncate(self.validator_count);
+        candidates.sort_by_key(|(key, _)| key.to_bytes());
+        self.active_validators = candidates;
+    }
+
+    /// Stake `key` holds in the active set, if it is in it.
+    pub fn active_stake(&self, key: &VerifyingKey) -> Option<u64> {
+        self.active_validators
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|&(_, stake)| stake)
+    }
+
+    /// Epoch `height` belongs to. Epoch `n` starts at height `n * epoch_length`.
+    pub fn epoch_of(&self, height: u64) -> u64 {
+        height / self.epoch_length.max(1)
+    }
+
+    /// Proposer of the block at `height` on top of `prev_hash`, drawn with probability
+    /// proportional to stake from a seed hashed from both.
+    pub fn select_proposer(&self, prev_hash: &[u8], height: u64) -> Option<VerifyingKey> {
+        let validators = &self.active_validators;
+        let total_stake: u128 = validators.iter().map(|&(_, stake)| stake as u128).sum();
+        if total_stake == 0 {
+            return None;
+        }
+
+        let seed: [u8; 32] = Sha256::new()
+            .chain_update(prev_hash)
+            .chain_update(height.to_le_bytes())
+            .finalize()
+            .into();
+        let mut ticket = u128::from_le_bytes(seed[..16].try_into().unwrap()) % total_stake;
+        for &(key, stake) in validators {
+            if ticket < stake as u128 {
+                return Some(key);
+            }
+            ticket -= stake as u128;
+        }
         None
     }
+
+    /// Height of the block this state validates next.
+    pub fn next_height(&self) -> u64 {
+        self.height.map_or(0, |height| height + 1)
+    }
+
+    /// Slot under way at `timestamp`, slot 0 before `genesis_time`.
+    pub fn slot_at(&self, timestamp: i64) -> u64 {
+        timestamp.saturating_sub(self.genesis_time).max(0) as u64 / self.slot_duration.max(1)
+    }
+
+    /// Timestamp `slot` begins at.
+    pub fn slot_start(&self, slot: u64) -> i64 {
+        let offset = slot.saturating_mul(self.slot_duration.max(1));
+        self.genesis_time
+            .saturating_add(i64::try_from(offset).unwrap_or(i64::MAX))
+    }
+
+    /// VRF input for `slot`: the epoch seed followed by the slot.
+    pub fn vrf_input(&self, slot: u64) -> Vec<u8> {
+        [self.epoch_seed.as_slice(), &slot.to_le_bytes()].concat()
+    }
+
+    /// Whether a VRF `output` of `key` makes it a slot leader under `Election::Vrf`:
+    /// its first 16 bytes, read as a fraction of 2^128, must be below
+    /// `active_slots_bps / 10000` times the validator's share of the active stake.
+    pub fn is_slot_leader(&self, key: &VerifyingKey, output: &[u8]) -> bool {
+        let Election::Vrf { active_slots_bps } = self.election else {
+            return false;
+        };
+        let total: u128 = self
+            .active_validators
+            .iter()
+            .map(|&(_, stake)| stake as u128)
+            .sum();
+        let Some(stake) = self.active_stake(key).filter(|_| total > 0) else {
+            return false;
+        };
+        let Some(prefix) = output.get(..16) else {
+            return false;
+        };
+        let threshold = ((BigUint::from(stake) * active_slots_bps) << 128u32)
+            / (BigUint::from(total) * 10_000u32);
+        BigUint::from_bytes_be(prefix) < threshold
+    }
+
+    /// VRF proof that the validator with `secret` leads `slot`, if it does.
+    pub fn prove_leadership(&self, secret: &SecretKey, slot: u64) -> Option<VrfData> {
+        let key = SigningKey::from_bytes(secret).verifying_key();
+        let (proof, output) = vrf::prove(secret, &self.vrf_input(slot));
+        self.is_slot_leader(&key, &output).then(|| VrfData {
+            output: output.to_vec(),
+            proof: proof.to_vec(),
+        })
+    }
+
+    /// First slot after the tip and no later than `now` led by a validator this node
+    /// holds the key of.
+    fn find_leader(&self, now: i64) -> Option<(u64, VerifyingKey, VrfData)> {
+        let last = self
+            .slot_at(now)
+            .min(self.last_slot.saturating_add(MAX_SLOT_SEARCH));
+        (self.last_slot + 1..=last).find_map(|slot| {
+            self.active_validators.iter().find_map(|(key, _)| {
+                let secret = self.validator_keys.get(key)?;
+                let vrf = self.prove_leadership(secret, slot)?;
+                Some((slot, *key, vrf))
+            })
+        })
+    }
+
+    fn check_vrf(&self, header: &BlockHeader<PoSData>) -> Result<(), ValidationError> {
+        let data = &header.data;
+        if data.slot <= self.last_slot {
+            return Err(ValidationError::SlotNotAfterParent {
+                slot: data.slot,
+                parent: self.last_slot,
+            });
+        }
+        if self.slot_at(header.timestamp) != data.slot {
+            return Err(ValidationError::SlotTimeMismatch {
+                slot: data.slot,
+                timestamp: header.timestamp,
+            });
+        }
+        let Some(claimed) = &data.vrf else {
+            return Err(ValidationError::BadVrfProof);
+        };
+        match vrf::verify(
+            &data.validator_key,
+            &self.vrf_input(data.slot),
+            &claimed.proof,
+        ) {
+            Some(output) if output.as_slice() == claimed.output => {}
+            _ => return Err(ValidationError::BadVrfProof),
+        }
+        if !self.is_slot_leader(&data.validator_key, &claimed.output) {
+            return Err(ValidationError::UnexpectedProposer);
+        }
+        Ok(())
+    }
 }
 
 impl Consensus for PoS {
     type Data = PoSData;
+    const NAME: &'static str = "PoS";
 
-    fn validate<T: Transaction>(&self, block: &Block<T, Self>) -> bool {
+    fn validate<T: Transaction>(&self, block: &Block<T, Self>) -> Result<(), ValidationError> {
         let pub_key = block.header.data.validator_key;
         let signature = block.header.data.signature;
 
+        if pub_key
+            .verify(&block.header.signing_hash(), &signature)
+            .is_err()
+        {
+            return Err(ValidationError::BadProposerSignature);
+        }
+
         // Check validator has sufficient stake in previous state
-        let has_stake = &self
+        let has_stake = self
             .cur_validators
             .get(&pub_key)
-            .map_or(false, |&stake| stake >= self.min_stake_amount);
+            .is_some_and(|&stake| stake >= self.min_stake_amount);
+        if !has_stake {
+            return Err(ValidationError::InsufficientStake);
+        }
+        if self.active_stake(&pub_key).is_none() {
+            return Err(ValidationError::InactiveValidator);
+        }
+
+        match self.election {
+            Election::Stake => {
+                if block.header.data.slot != self.next_height() {
+                    return Err(ValidationError::UnexpectedSlot {
+                        expected: self.next_height(),
+                        found: block.header.data.slot,
+                    });
+                }
+                let expected = self.select_proposer(&block.header.prev_hash, self.next_height());
+                if expected != Some(pub_key) {
+                    return Err(ValidationError::UnexpectedProposer);
+                }
+            }
+            Election::Vrf { .. } => self.check_vrf(&block.header)?,
+        }
+
+        // Replay the stake changes on a copy, as `apply_block` would.
+        let height = self.next_height();
+        let mut scratch: Option<Self> = None;
+        for (index, tx) in block.transactions().0.iter().enumerate() {
+            if let Some(op) = tx.stake_op() {
+                let state = scratch.get_or_insert_with(|| {
+                    let mut state = self.clone();
+                    state.release_unbonded(height);
+                    state
+                });
+                state.apply_stake_op(index, op, height)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_time(
+        &self,
+        header: &BlockHeader<Self::Data>,
+        now: i64,
+    ) -> Result<(), ValidationError> {
+        let current = self.slot_at(now);
+        match self.election {
+            Election::Vrf { .. } if header.data.slot > current => {
+                Err(ValidationError::SlotInFuture {
+                    slot: header.data.slot,
+                    current,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
 
-        pub_key.verify(&block.header.hash(), &signature).is_ok() && *has_stake
+    fn block_weight(&self, header: &BlockHeader<Self::Data>) -> BigUint {
+        BigUint::from(self.active_stake(&header.data.validator_key).unwrap_or(0))
+    }
+
+    fn apply_block<T: Transaction>(&mut self, height: u64, block: &Block<T, Self>) {
+        self.height = Some(height);
+        self.last_slot = block.header.data.slot;
+        self.jailed.retain(|_, until| *until > height);
+        // Genesis has no proposer.
+        if height > 0 {
+            self.issue_rewards(&block.header.data.validator_key);
+        }
+        self.release_unbonded(height);
+        for (index, tx) in block.transactions().0.iter().enumerate() {
+            if let Some(op) = tx.stake_op() {
+                // Checked by `validate`; a failing change is skipped.
+                let _ = self.apply_stake_op(index, op, height);
+            }
+        }
+        // The next block opens an epoch: it is validated with the new set and seed.
+        if self.epoch_of(height + 1) != self.epoch_of(height) {
+            self.rotate_validators();
+            self.epoch_seed = Sha256::new()
+                .chain_update(self.epoch_seed)
+                .chain_update(block.header.hash())
+                .finalize()
+                .into();
+        }
     }
 
     fn generate_block<T: Transaction>(
@@ -170,40 +850,78 @@ impl Consensus for PoS {
         block: &Block<T, Self>,
         txs: Transactions<T>,
     ) -> Result<Block<T, Self>> {
-        let Some(validator_pubkey) = self.select_validator() else {
-            bail!("No validator selected");
+        let now = Utc::now().timestamp();
+        let (slot, timestamp, validator_pubkey, vrf) = match self.election {
+            Election::Stake => {
+                let Some(key) = self.select_proposer(&block.header.hash(), self.next_height())
+                else {
+                    bail!("No validator selected");
+                };
+                let timestamp = now.max(block.header.timestamp + 1);
+                (self.next_height(), timestamp, key, None)
+            }
+            Election::Vrf { .. } => match self.find_leader(now) {
+                Some((slot, key, vrf)) => (slot, self.slot_start(slot), key, Some(vrf)),
+                None => bail!("No local validator leads any slot since the tip"),
+            },
         };
         let Some(secret_key) = self.validator_keys.get(&validator_pubkey) else {
             bail!("No secret key found");
         };
 
-        let hash = block.header.hash();
-        let signature = SigningKey::from_bytes(&secret_key).sign(&hash);
-
         let Some(merkle_root) = txs.merkle_root() else {
             bail!("No merkle root found");
         };
 
-        let block = Block {
+        let mut block: Block<T, Self> = Block {
             header: BlockHeader {
                 prev_hash: block.header.hash().to_vec(),
                 merkle_root,
-                timestamp: Utc::now().timestamp(),
+                timestamp,
                 data: PoSData {
                     validator_key: validator_pubkey,
-                    signature,
+                    signature: Signature::from_bytes(&[0; 64]),
+                    slot,
+                    vrf,
                 },
             },
             txs,
         };
+        block.header.data.signature =
+            SigningKey::from_bytes(secret_key).sign(&block.header.signing_hash());
 
         Ok(block)
     }
 
+    /// The interest rate, a fraction, becomes basis points and the active set is
+    /// drawn from the carried over stakes.
+    fn from_legacy_state(raw: &[u8]) -> Option<Self> {
+        let legacy: LegacyPoS = bincode::deserialize(raw).ok()?;
+        let rate = legacy.annual_interest_rate * BPS as f64;
+        if !(0.0..=u64::MAX as f64).contains(&rate) {
+            return None;
+        }
+        let mut state = Self {
+            min_stake_amount: legacy.min_stake_amount,
+            stake_lock_period: legacy.stake_lock_period,
+            annual_interest_rate: rate.round() as u64,
+            validator_count: legacy.validator_count,
+            epoch_length: legacy.epoch_length,
+            security_deposit: legacy.security_deposit,
+            cur_validators: legacy.cur_validators,
+            validator_keys: legacy.validator_keys,
+            ..Self::default()
+        };
+        state.rotate_validators();
+        Some(state)
+    }
+
     fn genesis_data() -> Self::Data {
         PoSData {
             validator_key: VerifyingKey::from_bytes(&[0; 32]).unwrap(),
             signature: Signature::from_bytes(&[0; 64]),
+            slot: 0,
+            vrf: None,
         }
     }
 }
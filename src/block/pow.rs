@@ -6,20 +6,49 @@ use num_bigint::BigUint;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    block::{Block, BlockHeader, Consensus, Transaction},
+    block::{
+        Block, BlockHeader, Consensus, Transaction,
+        error::ValidationError,
+        pow_hash::{PowAlgorithm, PowHasher},
+        retarget::{Retarget, RetargetParams, RetargetPoint},
+    },
     chain::blockchain_control,
-    hash::{Hashable, bits_to_target},
+    hash::{Hashable, bits_to_target, target_to_bits},
+    mining::miner::Miner,
 };
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PoW {
     pub target_timespan: u64,
     pub difficulty_adjust_interval: u64,
+    /// Bits of genesis, also the easiest difficulty retargeting may reach.
     pub initial_difficulty: u32,
+    /// Without it a coinbase may only collect fees.
     pub allow_mining_reward: bool,
+    /// Subsidy of the first block, then scaled by `subsidy`.
     pub block_reward: u64,
-    // cur difficulty
+    pub subsidy: Subsidy,
+    pub coinbase_maturity: u64,
+    pub retarget: Retarget,
+    pub pow_hash: PowAlgorithm,
+    /// Bits required of the next block.
     pub cur_bits: u32,
+    /// Most recent main chain blocks, as many as `retarget` looks at.
+    pub history: Vec<RetargetPoint>,
+    /// First block applied to this state.
+    pub anchor: Option<RetargetPoint>,
+}
+
+/// `PoW` as stored by the flat key layout.
+#[derive(Deserialize)]
+struct LegacyPoW {
+    target_timespan: u64,
+    difficulty_adjust_interval: u64,
+    initial_difficulty: u32,
+    allow_mining_reward: bool,
+    block_reward: u64,
+    // Bits of the tip, recomputed by replaying the chain.
+    _cur_bits: u32,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -42,16 +71,84 @@ impl Default for PoW {
             initial_difficulty: blockchain_control::DEFAULT_DIFFICULTY,
             allow_mining_reward: true,
             block_reward: 50,
+            subsidy: Subsidy::Fixed,
+            coinbase_maturity: blockchain_control::COINBASE_MATURITY,
+            retarget: Retarget::Interval,
+            pow_hash: PowAlgorithm::Sha256d,
             cur_bits: blockchain_control::DEFAULT_DIFFICULTY,
+            history: Vec::new(),
+            anchor: None,
         }
     }
 }
 
 impl Consensus for PoW {
     type Data = PoWData;
-    fn validate<T: Transaction>(&self, block: &Block<T, Self>) -> bool {
-        let target = bits_to_target(self.cur_bits);
-        BigUint::from_bytes_be(&block.header.hash()) <= target
+    const NAME: &'static str = "PoW";
+    fn validate<T: Transaction>(&self, block: &Block<T, Self>) -> Result<(), ValidationError> {
+        let data = &block.header.data;
+        if data.bits != self.cur_bits {
+            return Err(ValidationError::UnexpectedBits {
+                expected: self.cur_bits,
+                found: data.bits,
+            });
+        }
+        if !data.is_valid(&self.pow_hash.pow_hash(&block.header.preimage())) {
+            return Err(ValidationError::InsufficientWork);
+        }
+
+        let allowed = block
+            .fees()
+            .and_then(|fees| self.subsidy(self.next_height()).checked_add(fees))
+            .ok_or(ValidationError::FeeOverflow)?;
+        if let Some(claimed) = block.coinbase().and_then(|tx| tx.coinbase_amount())
+            && claimed > allowed
+        {
+            return Err(ValidationError::ExcessiveCoinbase { claimed, allowed });
+        }
+        Ok(())
+    }
+
+    fn coinbase_maturity(&self) -> u64 {
+        self.coinbase_maturity
+    }
+
+    fn from_legacy_state(raw: &[u8]) -> Option<Self> {
+        let legacy: LegacyPoW = bincode::deserialize(raw).ok()?;
+        Some(Self {
+            target_timespan: legacy.target_timespan,
+            difficulty_adjust_interval: legacy.difficulty_adjust_interval,
+            initial_difficulty: legacy.initial_difficulty,
+            allow_mining_reward: legacy.allow_mining_reward,
+            block_reward: legacy.block_reward,
+            cur_bits: legacy.initial_difficulty,
+            ..Self::default()
+        })
+    }
+
+    fn apply_block<T: Transaction>(&mut self, height: u64, block: &Block<T, Self>) {
+        let point = RetargetPoint {
+            height,
+            timestamp: block.header.timestamp,
+            bits: block.header.data.bits,
+        };
+        let anchor = *self.anchor.get_or_insert(point);
+        let params = self.retarget_params();
+        self.history.push(point);
+        let excess = self
+            .history
+            .len()
+            .saturating_sub(self.retarget.history_len(&params));
+        self.history.drain(..excess);
+
+        self.cur_bits = match self.retarget.next_target(&self.history, &anchor, &params) {
+            Some(target) => target_to_bits(target),
+            None => point.bits,
+        };
+    }
+
+    fn block_weight(&self, header: &BlockHeader<Self::Data>) -> BigUint {
+        header.data.work()
     }
 
     fn generate_block<T: Transaction>(
@@ -64,39 +161,20 @@ impl Consensus for PoW {
             None => bail!("No merkle root found!"),
         };
 
-        let mut block = Block {
-            header: BlockHeader {
-                prev_hash: prev.header.hash().to_vec(),
-                merkle_root,
-                timestamp: Utc::now().timestamp(),
-                data: PoWData {
-                    bits: self.cur_bits,
-                    nonce: 0,
-                },
+        let header = BlockHeader {
+            prev_hash: prev.header.hash().to_vec(),
+            merkle_root,
+            timestamp: Utc::now().timestamp().max(prev.header.timestamp + 1),
+            data: PoWData {
+                bits: self.cur_bits,
+                nonce: 0,
             },
-            txs,
         };
 
-        let bh: &mut BlockHeader<PoWData> = &mut block.header;
-        let mut nonce = 0u64;
-        loop {
-            bh.data.nonce = nonce;
-            let hash = bh.hash();
-
-            if bh.data.is_valid(&hash) {
-                log::debug!("Found block with nonce {}", nonce);
-                break;
-            }
-
-            nonce = nonce.wrapping_add(1);
-
-            if nonce % 1_000_000 == 0 {
-                bh.timestamp = Utc::now().timestamp();
-                log::debug!("Retrying with timestamp {}", bh.timestamp);
-            }
+        match Miner::default().mine(header, &self.pow_hash) {
+            Some(header) => Ok(Block { header, txs }),
+            None => bail!("No nonce meets the target"),
         }
-
-        Ok(block)
     }
 
     fn genesis_data() -> Self::Data {
@@ -107,38 +185,94 @@ impl Consensus for PoW {
     }
 }
 
+/// How the block subsidy evolves from `PoW::block_reward`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Subsidy {
+    Fixed,
+    /// Halves every `interval` blocks until it reaches zero.
+    Halving {
+        interval: u64,
+    },
+    /// Halves every `interval` blocks but never drops below `tail`.
+    HalvingWithTail {
+        interval: u64,
+        tail: u64,
+    },
+}
+
+impl PoW {
+    /// Newly minted amount a coinbase at `height` may claim on top of fees.
+    pub fn subsidy(&self, height: u64) -> u64 {
+        if !self.allow_mining_reward {
+            return 0;
+        }
+        let halved = |interval: u64| {
+            self.block_reward
+                .checked_shr((height / interval.max(1)).min(64) as u32)
+                .unwrap_or(0)
+        };
+        match self.subsidy {
+            Subsidy::Fixed => self.block_reward,
+            Subsidy::Halving { interval } => halved(interval),
+            Subsidy::HalvingWithTail { interval, tail } => halved(interval).max(tail),
+        }
+    }
+
+    /// Height of the block this state validates next.
+    pub fn next_height(&self) -> u64 {
+        self.history.last().map_or(0, |point| point.height + 1)
+    }
+
+    pub fn retarget_params(&self) -> RetargetParams {
+        RetargetParams {
+            interval: self.difficulty_adjust_interval,
+            timespan: self.target_timespan,
+            limit: bits_to_target(self.initial_difficulty),
+        }
+    }
+}
+
 impl PoWData {
     pub fn target(&self) -> BigUint {
         bits_to_target(self.bits)
     }
 
+    /// Expected number of hashes to meet the target, `2^256 / (target + 1)`.
+    pub fn work(&self) -> BigUint {
+        (BigUint::from(1u8) << 256) / (self.target() + 1u8)
+    }
+
     pub fn is_valid(&self, hash: &[u8]) -> bool {
         BigUint::from_bytes_be(hash) <= self.target()
     }
 
-    pub fn run(&self, mut bh: BlockHeader<PoWData>) -> BlockHeader<PoWData> {
-        let mut nonce = 0u64;
-        loop {
-            bh.data.nonce = nonce;
-            let hash = bh.hash();
-
-            if self.is_valid(&hash) {
-                log::debug!("Found block with nonce {}", nonce);
-                return bh;
-            }
-
-            nonce = nonce.wrapping_add(1);
-
-            if nonce % 1_000_000 == 0 {
-                bh.timestamp = Utc::now().timestamp();
-                log::debug!("Retrying with timestamp {}", bh.timestamp);
-            }
+    /// Target as 32 big-endian bytes, so hashes compare against it bytewise.
+    pub fn target_bytes(&self) -> [u8; 32] {
+        let bytes = self.target().to_bytes_be();
+        if bytes.len() > 32 {
+            return [0xff; 32];
         }
+        let mut padded = [0u8; 32];
+        padded[32 - bytes.len()..].copy_from_slice(&bytes);
+        padded
     }
 }
 
 impl<T: Transaction + Serialize> Block<T, PoW> {
+    /// Mines the header in place with the default `PowAlgorithm::Sha256d`.
     pub fn mine(&mut self) {
-        self.header = self.header.data.run(self.header.clone())
+        self.mine_with(&Miner::default(), &PowAlgorithm::default());
+    }
+
+    /// Mines the header in place. Returns `false`, leaving the block unchanged, if
+    /// `miner` was cancelled or no nonce works.
+    pub fn mine_with(&mut self, miner: &Miner, hasher: &(impl PowHasher + Sync)) -> bool {
+        match miner.mine(self.header.clone(), hasher) {
+            Some(header) => {
+                self.header = header;
+                true
+            }
+            None => false,
+        }
     }
 }
@@ -0,0 +1,48 @@
+//! Proof-of-work hash functions.
+//!
+//! The proof hash is computed over `BlockHeader::preimage` and compared against the
+//! target. It may differ from the block id, which is always `BlockHeader::hash`.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+pub trait PowHasher {
+    fn pow_hash(&self, preimage: &[u8]) -> [u8; 32];
+}
+
+/// Proof hash selected by `PoW::pow_hash`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum PowAlgorithm {
+    /// Double SHA-256, identical to the block id.
+    #[default]
+    Sha256d,
+    Blake3,
+    /// scrypt with `2^log_n` rounds, using the preimage as password and salt. Memory
+    /// use is about `128 * r * 2^log_n` bytes per hash.
+    Scrypt {
+        log_n: u8,
+        r: u32,
+        p: u32,
+    },
+}
+
+impl PowHasher for PowAlgorithm {
+    fn pow_hash(&self, preimage: &[u8]) -> [u8; 32] {
+        match *self {
+            Self::Sha256d => Sha256::digest(Sha256::digest(preimage)).into(),
+            Self::Blake3 => blake3::hash(preimage).into(),
+            Self::Scrypt { log_n, r, p } => {
+                let mut out = [0u8; 32];
+                match scrypt::Params::new(log_n, r, p, out.len()) {
+                    Ok(params) => {
+                        // Output length is fixed and valid for these params.
+                        let _ = scrypt::scrypt(preimage, preimage, &params, &mut out);
+                        out
+                    }
+                    // Unusable parameters never meet a target.
+                    Err(_) => [0xff; 32],
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,137 @@
+//! Difficulty retargeting rules for `PoW`.
+//!
+//! Each rule maps the recent main chain history to the target of the next block.
+//! `PoW` keeps that history in its state, so the expected bits of every block follow
+//! from the state snapshot of its parent.
+
+use num_bigint::BigUint;
+use serde::{Deserialize, Serialize};
+
+use crate::hash::bits_to_target;
+
+/// Lower bound on any computed target, keeping it representable as compact bits.
+const MIN_TARGET_BITS: u32 = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Retarget {
+    /// Every `PoW::difficulty_adjust_interval` blocks, scales the target by how long
+    /// the interval took against `PoW::target_timespan`, by at most 4x either way.
+    Interval,
+    /// Linearly weighted moving average over the last `window` solve times, adjusted
+    /// every block.
+    Lwma { window: u64 },
+    /// Absolutely scheduled exponential adjustment against the first block: the
+    /// target doubles for every `half_life` seconds the chain is behind schedule and
+    /// halves for every `half_life` it is ahead.
+    Asert { half_life: u64 },
+}
+
+/// What a retarget rule needs to know about a block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetargetPoint {
+    pub height: u64,
+    pub timestamp: i64,
+    pub bits: u32,
+}
+
+/// Chain parameters shared by every rule.
+#[derive(Debug, Clone)]
+pub struct RetargetParams {
+    pub interval: u64,
+    pub timespan: u64,
+    /// Easiest allowed target.
+    pub limit: BigUint,
+}
+
+impl RetargetParams {
+    /// Target seconds between blocks.
+    pub fn spacing(&self) -> i64 {
+        (self.timespan / self.interval.max(1)).max(1) as i64
+    }
+
+    fn clamp(&self, target: BigUint) -> BigUint {
+        target.clamp(BigUint::from(1u8) << MIN_TARGET_BITS, self.limit.clone())
+    }
+}
+
+impl Retarget {
+    /// Number of most recent blocks, tip included, the rule looks at.
+    pub fn history_len(&self, params: &RetargetParams) -> usize {
+        match self {
+            Self::Interval => params.interval as usize + 1,
+            Self::Lwma { window } => *window as usize + 1,
+            Self::Asert { .. } => 1,
+        }
+    }
+
+    /// Target of the block after `history.last()`, or `None` to keep the tip's bits.
+    /// `anchor` is the first block the state saw, normally genesis.
+    pub fn next_target(
+        &self,
+        history: &[RetargetPoint],
+        anchor: &RetargetPoint,
+        params: &RetargetParams,
+    ) -> Option<BigUint> {
+        let tip = history.last()?;
+        let target = match self {
+            Self::Interval => {
+                if tip.height == 0 || !tip.height.is_multiple_of(params.interval) {
+                    return None;
+                }
+                let first =
+                    history.get(history.len().checked_sub(params.interval as usize + 1)?)?;
+                let span = (tip.timestamp - first.timestamp).max(1) as u64;
+                let prev = bits_to_target(tip.bits);
+                (prev.clone() * params.timespan / span).clamp(prev.clone() / 4u32, prev * 4u32)
+            }
+            Self::Lwma { .. } => lwma(history, params)?,
+            Self::Asert { half_life } => asert(tip, anchor, *half_life, params),
+        };
+        Some(params.clamp(target))
+    }
+}
+
+fn lwma(history: &[RetargetPoint], params: &RetargetParams) -> Option<BigUint> {
+    let n = history.len().checked_sub(1).filter(|&n| n > 0)? as u64;
+    let spacing = params.spacing();
+    let mut weighted_time = 0u64;
+    let mut target_sum = BigUint::default();
+    for (i, pair) in history.windows(2).enumerate() {
+        let solve_time = (pair[1].timestamp - pair[0].timestamp).clamp(1, 6 * spacing);
+        weighted_time += (i as u64 + 1) * solve_time as u64;
+        target_sum += bits_to_target(pair[1].bits);
+    }
+    let expected_weighted = n * (n + 1) / 2 * spacing as u64;
+    Some(target_sum / n * weighted_time / expected_weighted)
+}
+
+/// aserti3-2d: `2^x` for the fractional part of the exponent comes from a cubic
+/// approximation in 16.16 fixed point.
+fn asert(
+    tip: &RetargetPoint,
+    anchor: &RetargetPoint,
+    half_life: u64,
+    params: &RetargetParams,
+) -> BigUint {
+    let time_delta = (tip.timestamp - anchor.timestamp) as i128;
+    let height_delta = (tip.height - anchor.height) as i128;
+    let exponent =
+        (time_delta - params.spacing() as i128 * height_delta) * 65536 / half_life.max(1) as i128;
+    // Anything past 256 bits is clamped to the limits anyway.
+    let shifts = (exponent >> 16).clamp(-256, 256);
+    let frac = (exponent & 0xffff) as u128;
+    let factor = 65536
+        + ((195_766_423_245_049 * frac
+            + 971_821_376 * frac * frac
+            + 5127 * frac * frac * frac
+            + (1 << 47))
+            >> 48);
+
+    let target = bits_to_target(anchor.bits) * BigUint::from(factor);
+    let target = if shifts >= 0 {
+        target << shifts as u64
+    } else {
+        target >> shifts.unsigned_abs() as u64
+    };
+    target >> 16u32
+}
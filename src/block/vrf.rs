@@ -0,0 +1,121 @@
+//! ECVRF-EDWARDS25519-SHA512-TAI from RFC 9381, over ed25519 keys.
+//!
+//! Only the holder of a secret key can compute its output for an input, and anyone
+//! with the public key can check a proof of it. Each key has exactly one output per
+//! input, so unlike an ed25519 signature it cannot be ground for a better value.
+
+use curve25519_dalek::{
+    EdwardsPoint, Scalar,
+    edwards::CompressedEdwardsY,
+    scalar::clamp_integer,
+    traits::{Identity, IsIdentity},
+};
+use ed25519_dalek::{SecretKey, VerifyingKey};
+use sha2::{Digest, Sha512};
+
+pub const PROOF_LENGTH: usize = 80;
+pub const OUTPUT_LENGTH: usize = 64;
+
+const SUITE: u8 = 0x03;
+const CHALLENGE_LENGTH: usize = 16;
+
+/// Proof `pi` and output `beta` for `alpha` under `secret`.
+pub fn prove(secret: &SecretKey, alpha: &[u8]) -> ([u8; PROOF_LENGTH], [u8; OUTPUT_LENGTH]) {
+    let hashed: [u8; 64] = Sha512::digest(secret).into();
+    let x = Scalar::from_bytes_mod_order(clamp_integer(hashed[..32].try_into().unwrap()));
+    let public = EdwardsPoint::mul_base(&x).compress();
+
+    let h = encode_to_curve(&public, alpha);
+    let h_string = h.compress();
+    let gamma = x * h;
+    let k = Scalar::from_bytes_mod_order_wide(
+        &Sha512::new()
+            .chain_update(&hashed[32..])
+            .chain_update(h_string.as_bytes())
+            .finalize()
+            .into(),
+    );
+    let c = challenge(&[
+        public,
+        h_string,
+        gamma.compress(),
+        EdwardsPoint::mul_base(&k).compress(),
+        (k * h).compress(),
+    ]);
+    let s = k + c * x;
+
+    let mut proof = [0u8; PROOF_LENGTH];
+    proof[..32].copy_from_slice(gamma.compress().as_bytes());
+    proof[32..48].copy_from_slice(&c.as_bytes()[..CHALLENGE_LENGTH]);
+    proof[48..].copy_from_slice(s.as_bytes());
+    (proof, proof_to_hash(&gamma))
+}
+
+/// Output proven by `proof` for `alpha` under `public`, or `None` if the proof is
+/// malformed or invalid.
+pub fn verify(public: &VerifyingKey, alpha: &[u8], proof: &[u8]) -> Option<[u8; OUTPUT_LENGTH]> {
+    let proof: &[u8; PROOF_LENGTH] = proof.try_into().ok()?;
+    let public = CompressedEdwardsY(public.to_bytes());
+    let y = public.decompress()?;
+    if y.is_small_order() {
+        return None;
+    }
+    let gamma = CompressedEdwardsY(proof[..32].try_into().unwrap()).decompress()?;
+    let mut c_bytes = [0u8; 32];
+    c_bytes[..CHALLENGE_LENGTH].copy_from_slice(&proof[32..48]);
+    let c = Scalar::from_bytes_mod_order(c_bytes);
+    let s = Option::<Scalar>::from(Scalar::from_canonical_bytes(
+        proof[48..].try_into().unwrap(),
+    ))?;
+
+    let h = encode_to_curve(&public, alpha);
+    let u = EdwardsPoint::mul_base(&s) - c * y;
+    let v = s * h - c * gamma;
+    let expected = challenge(&[
+        public,
+        h.compress(),
+        gamma.compress(),
+        u.compress(),
+        v.compress(),
+    ]);
+    (expected == c).then(|| proof_to_hash(&gamma))
+}
+
+/// Try-and-increment hash to a point in the prime order subgroup.
+fn encode_to_curve(public: &CompressedEdwardsY, alpha: &[u8]) -> EdwardsPoint {
+    (0..=u8::MAX)
+        .find_map(|ctr| {
+            let hash = Sha512::new()
+                .chain_update([SUITE, 0x01])
+                .chain_update(public.as_bytes())
+                .chain_update(alpha)
+                .chain_update([ctr, 0x00])
+                .finalize();
+            let point = CompressedEdwardsY(hash[..32].try_into().unwrap())
+                .decompress()?
+                .mul_by_cofactor();
+            (!point.is_identity()).then_some(point)
+        })
+        // Fails with probability 2^-256.
+        .unwrap_or_else(EdwardsPoint::identity)
+}
+
+fn challenge(points: &[CompressedEdwardsY; 5]) -> Scalar {
+    let mut hasher = Sha512::new().chain_update([SUITE, 0x02]);
+    for point in points {
+        hasher.update(point.as_bytes());
+    }
+    let hash = hasher.chain_update([0x00]).finalize();
+    let mut c = [0u8; 32];
+    c[..CHALLENGE_LENGTH].copy_from_slice(&hash[..CHALLENGE_LENGTH]);
+    Scalar::from_bytes_mod_order(c)
+}
+
+fn proof_to_hash(gamma: &EdwardsPoint) -> [u8; OUTPUT_LENGTH] {
+    Sha512::new()
+        .chain_update([SUITE, 0x03])
+        .chain_update(gamma.mul_by_cofactor().compress().as_bytes())
+        .chain_update([0x00])
+        .finalize()
+        .into()
+}
@@ -0,0 +1,166 @@
+//! Portable chain archives.
+//!
+//! Layout, integers little-endian:
+//!
+//! ```text
+//! magic "BCAR" | version u16 | header len u32 | bincode ArchiveHeader
+//! per block:   len u32 | bincode Block | sha256(block bytes)
+//! ```
+
+use std::io::{Read, Write};
+
+use anyhow::{Result, bail};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    block::{Block, Consensus, Transaction},
+    hash::Hashable,
+};
+
+use super::{BlockChain, store::ChainStore};
+
+pub const ARCHIVE_MAGIC: &[u8; 4] = b"BCAR";
+pub const ARCHIVE_VERSION: u16 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArchiveHeader {
+    /// `Consensus::NAME` of the exporting chain.
+    pub consensus: String,
+    pub genesis_hash: Vec<u8>,
+    pub from: u64,
+    pub to: u64,
+}
+
+impl ArchiveHeader {
+    pub fn block_count(&self) -> u64 {
+        self.to - self.from + 1
+    }
+}
+
+impl<C: Consensus + for<'a> Deserialize<'a>, S: ChainStore> BlockChain<C, S> {
+    /// Writes main chain blocks `from..=to` as an archive. Returns the number of blocks written.
+    pub fn export_range<T: Transaction + for<'a> Deserialize<'a>>(
+        &self,
+        from: u64,
+        to: u64,
+        mut writer: impl Write,
+    ) -> Result<u64> {
+        if from > to || to > self.get_height()? {
+            bail!("Invalid export range {}..={}", from, to);
+        }
+        let header = ArchiveHeader {
+            consensus: C::NAME.to_string(),
+            genesis_hash: self.genesis_hash()?,
+            from,
+            to,
+        };
+
+        writer.write_all(ARCHIVE_MAGIC)?;
+        writer.write_all(&ARCHIVE_VERSION.to_le_bytes())?;
+        write_frame(&mut writer, &bincode::serialize(&header)?)?;
+
+        for block in self.iter_blocks::<T>(from..=to)? {
+            let raw = bincode::serialize(&block?)?;
+            write_frame(&mut writer, &raw)?;
+            writer.write_all(&Sha256::digest(&raw))?;
+        }
+        writer.flush()?;
+        Ok(header.block_count())
+    }
+
+    /// Reads an archive and adds its blocks through `add_block`, so every block is
+    /// validated again. Returns the number of blocks added.
+    ///
+    /// The whole archive is read, and its checksums, encoding and block linkage
+    /// checked, before the first block is added, so a damaged archive adds nothing.
+    /// A block failing validation stops the import with the blocks before it added.
+    /// Blocks already stored are skipped, so importing again resumes from there.
+    pub fn import<T: Transaction + for<'a> Deserialize<'a>>(
+        &mut self,
+        mut reader: impl Read,
+    ) -> Result<u64> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != ARCHIVE_MAGIC {
+            bail!("Not a chain archive");
+        }
+        let mut version = [0u8; 2];
+        reader.read_exact(&mut version)?;
+        let version = u16::from_le_bytes(version);
+        if version != ARCHIVE_VERSION {
+            bail!("Unsupported archive version {}", version);
+        }
+
+        let header: ArchiveHeader = bincode::deserialize(&read_frame(&mut reader)?)?;
+        if header.consensus != C::NAME {
+            bail!(
+                "Archive consensus {} does not match {}",
+                header.consensus,
+                C::NAME
+            );
+        }
+        if header.genesis_hash != self.genesis_hash()? {
+            bail!("Archive genesis does not match this chain");
+        }
+
+        let mut blocks: Vec<Block<T, C>> = Vec::new();
+        for height in header.from..=header.to {
+            let raw = read_frame(&mut reader)?;
+            let mut checksum = [0u8; 32];
+            reader.read_exact(&mut checksum)?;
+            if Sha256::digest(&raw).as_slice() != checksum {
+                bail!("Checksum mismatch for block at height {}", height);
+            }
+
+            let block: Block<T, C> = bincode::deserialize(&raw)?;
+            if let Some(prev) = blocks.last()
+                && block.header.prev_hash != prev.header.hash()
+            {
+                bail!(
+                    "Archive block at height {} does not extend its parent",
+                    height
+                );
+            }
+            blocks.push(block);
+        }
+
+        let mut imported = 0;
+        for block in blocks {
+            if self.get_meta(&block.header.hash())?.is_some() {
+                continue;
+            }
+            self.add_block(block)?;
+            imported += 1;
+        }
+        Ok(imported)
+    }
+
+    fn genesis_hash(&self) -> Result<Vec<u8>> {
+        self.get_hash(0)?
+            .ok_or_else(|| anyhow::anyhow!("Genesis block not found"))
+    }
+}
+
+fn write_frame(writer: &mut impl Write, bytes: &[u8]) -> Result<()> {
+    writer.write_all(&u32::try_from(bytes.len())?.to_le_bytes())?;
+    writer.write_all(bytes)?;
+    Ok(())
+}
+
+fn read_frame(reader: &mut impl Read) -> Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as u64;
+    // Grow with the bytes actually read, so a bogus length can't force a large allocation.
+    let mut bytes = Vec::new();
+    reader.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        bail!(
+            "Archive truncated: frame of {} bytes ends after {}",
+            len,
+            bytes.len()
+        );
+    }
+    Ok(bytes)
+}
@@ -0,0 +1,248 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{Result, anyhow, bail};
+use serde::Deserialize;
+
+use crate::{
+    block::{Block, BlockHeader, Consensus, Transaction, Transactions, error::ValidationError},
+    hash::Hashable,
+};
+
+use super::{
+    BlockChain, BlockMeta, DbKeys,
+    store::{ChainStore, Column, IterDirection, StoreBatch},
+};
+
+/// A problem found by `BlockChain::verify_integrity`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IntegrityIssue {
+    /// No `Column::Heights` entry for a height at or below the tip.
+    MissingHash { height: u64 },
+    /// The header or body is missing or cannot be decoded.
+    MissingBlock { height: u64, hash: Vec<u8> },
+    /// The stored header does not hash to its key.
+    HashMismatch { height: u64, hash: Vec<u8> },
+    /// The block fails the checks `add_block` applies against its parent, the future
+    /// drift rules aside: local time has moved on since it was added.
+    Invalid { height: u64, error: ValidationError },
+    /// The block could not be checked against its parent, e.g. because the parent's
+    /// state or an ancestor could not be read.
+    Unchecked { height: u64, reason: String },
+    /// The consensus state snapshot is missing or cannot be decoded.
+    BadState { height: u64 },
+    /// `CUR_HEIGHT` or `LAST_HASH` disagree with the height index.
+    TipMismatch { height: u64 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IntegrityReport {
+    /// `CUR_HEIGHT` when the check ran.
+    pub tip_height: u64,
+    /// Highest height below the first damaged block; `None` if genesis is damaged.
+    pub consistent_height: Option<u64>,
+    pub issues: Vec<IntegrityIssue>,
+}
+
+impl IntegrityReport {
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+impl<C: Consensus + for<'a> Deserialize<'a>, S: ChainStore> BlockChain<C, S> {
+    /// Walks the main chain from genesis, re-checking every stored block against its
+    /// key, its parent and the consensus rules.
+    pub fn verify_integrity<T: Transaction + for<'a> Deserialize<'a>>(
+        &self,
+    ) -> Result<IntegrityReport> {
+        let tip_height = self.get_height()?;
+        let end = tip_height.max(self.last_indexed_height()?.unwrap_or(0));
+
+        let mut issues = Vec::new();
+        let mut first_bad = None;
+        let mut prev: Option<Block<T, C>> = None;
+        for height in 0..=end {
+            let found = issues.len();
+            prev = self.check_height(height, prev.as_ref(), &mut issues);
+            if issues.len() > found {
+                first_bad.get_or_insert(height);
+            }
+        }
+
+        let tip_hash = self.get_tip_hash().ok();
+        if end > tip_height || tip_hash.is_none() || tip_hash != self.get_hash(tip_height)? {
+            issues.push(IntegrityIssue::TipMismatch { height: tip_height });
+        }
+
+        let consistent_height = match first_bad {
+            Some(height) => height.checked_sub(1),
+            None => Some(end),
+        };
+        Ok(IntegrityReport {
+            tip_height,
+            consistent_height,
+            issues,
+        })
+    }
+
+    /// Runs `verify_integrity` and, if it finds problems, truncates the main chain to
+    /// the last consistent height and restores the consensus state recorded there,
+    /// replaying blocks if that snapshot is missing. Blocks above it, and the side
+    /// branches built on them, are removed from the block tree so they can be added
+    /// again. Returns the report from before the repair.
+    pub fn repair<T: Transaction + for<'a> Deserialize<'a>>(&mut self) -> Result<IntegrityReport> {
+        let report = self.verify_integrity::<T>()?;
+        if report.is_ok() {
+            return Ok(report);
+        }
+        let Some(keep) = report.consistent_height else {
+            bail!("Genesis block is damaged, chain cannot be repaired");
+        };
+        let keep_hash = self
+            .get_hash(keep)?
+            .ok_or_else(|| anyhow!("Block hash not found at height {}", keep))?;
+
+        let end = report
+            .tip_height
+            .max(self.last_indexed_height()?.unwrap_or(0));
+        let mut batch = StoreBatch::default();
+        let mut removed = HashSet::new();
+        for height in keep + 1..=end {
+            batch.delete(Column::Heights, DbKeys::height_key(height));
+            let Some(hash) = self.get_hash(height)? else {
+                continue;
+            };
+            if self.tx_index
+                && let Some(block) = self.read_block::<T>(&hash)
+            {
+                for tx in &block.transactions().0 {
+                    batch.delete(Column::Indexes, DbKeys::tx_key(&tx.hash()));
+                }
+            }
+            removed.insert(hash);
+        }
+        // Side branch blocks are not in the transaction index.
+        for hash in removed.iter().chain(&self.descendants(&removed)?) {
+            batch.delete(Column::Headers, hash);
+            batch.delete(Column::Bodies, hash);
+            batch.delete(Column::Indexes, DbKeys::meta_key(hash));
+            batch.delete(Column::State, DbKeys::state_key(hash));
+        }
+        let state = self.parent_state::<T>(&keep_hash)?;
+        let state_raw = bincode::serialize(&state)?;
+        batch.put(Column::State, DbKeys::LAST_HASH, &keep_hash);
+        batch.put(Column::State, DbKeys::CUR_HEIGHT, keep.to_le_bytes());
+        batch.put(Column::State, DbKeys::state_key(&keep_hash), &state_raw);
+        batch.put(Column::State, DbKeys::CUR_STATE, state_raw);
+        self.store.write(batch)?;
+        self.cs = state;
+
+        log::warn!(
+            "Repaired chain: truncated from height {} to {}",
+            report.tip_height,
+            keep
+        );
+        Ok(report)
+    }
+
+    /// Checks one height, returning the block if it can serve as the next parent.
+    fn check_height<T: Transaction + for<'a> Deserialize<'a>>(
+        &self,
+        height: u64,
+        prev: Option<&Block<T, C>>,
+        issues: &mut Vec<IntegrityIssue>,
+    ) -> Option<Block<T, C>> {
+        let Ok(Some(hash)) = self.get_hash(height) else {
+            issues.push(IntegrityIssue::MissingHash { height });
+            return None;
+        };
+
+        let Some(block) = self.read_block::<T>(&hash) else {
+            issues.push(IntegrityIssue::MissingBlock { height, hash });
+            return None;
+        };
+        if block.header.hash().as_slice() != hash {
+            issues.push(IntegrityIssue::HashMismatch { height, hash });
+            return None;
+        }
+
+        if let Some(prev) = prev {
+            let checked = self
+                .parent_state::<T>(&block.header.prev_hash)
+                .and_then(|state| {
+                    Self::validate_new(&state, &block, prev)?;
+                    self.check_median_time(&block.header)?;
+                    self.check_coinbase_maturity(&state, &block)
+                });
+            if let Err(e) = checked {
+                issues.push(match e.downcast_ref::<ValidationError>() {
+                    Some(error) => IntegrityIssue::Invalid {
+                        height,
+                        error: error.clone(),
+                    },
+                    None => IntegrityIssue::Unchecked {
+                        height,
+                        reason: e.to_string(),
+                    },
+                });
+            }
+        }
+        if !matches!(self.state_of(&hash), Ok(Some(_))) {
+            issues.push(IntegrityIssue::BadState { height });
+        }
+        Some(block)
+    }
+
+    fn read_block<T: Transaction + for<'a> Deserialize<'a>>(
+        &self,
+        hash: &[u8],
+    ) -> Option<Block<T, C>> {
+        let header = self.store.get(Column::Headers, hash).ok()??;
+        let body = self.store.get(Column::Bodies, hash).ok()??;
+        let header: BlockHeader<C::Data> = bincode::deserialize(&header).ok()?;
+        let txs: Transactions<T> = bincode::deserialize(&body).ok()?;
+        Some(Block::from_parts(header, txs))
+    }
+
+    /// Blocks of any branch descending from one of `roots`, `roots` excluded.
+    fn descendants(&self, roots: &HashSet<Vec<u8>>) -> Result<Vec<Vec<u8>>> {
+        let prefix = DbKeys::meta_key(&[]);
+        let mut children: HashMap<Vec<u8>, Vec<Vec<u8>>> = HashMap::new();
+        for entry in self.store.prefix_iter(Column::Indexes, &prefix) {
+            let (key, raw) = entry?;
+            let Ok(meta) = bincode::deserialize::<BlockMeta>(&raw) else {
+                continue;
+            };
+            children
+                .entry(meta.prev_hash)
+                .or_default()
+                .push(key[prefix.len()..].to_vec());
+        }
+
+        let mut found = Vec::new();
+        let mut pending: Vec<_> = roots.iter().cloned().collect();
+        while let Some(hash) = pending.pop() {
+            for child in children.remove(&hash).unwrap_or_default() {
+                if !roots.contains(&child) {
+                    found.push(child.clone());
+                }
+                pending.push(child);
+            }
+        }
+        Ok(found)
+    }
+
+    /// Highest height with a `Column::Heights` entry, which may lie past `CUR_HEIGHT`.
+    fn last_indexed_height(&self) -> Result<Option<u64>> {
+        let last = self
+            .store
+            .iter_from(
+                Column::Heights,
+                &DbKeys::height_key(u64::MAX),
+                IterDirection::Reverse,
+            )
+            .next()
+            .transpose()?;
+        Ok(last.and_then(|(key, _)| DbKeys::height_from_key(&key)))
+    }
+}
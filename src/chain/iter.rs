@@ -0,0 +1,154 @@
+use std::ops::{Bound, RangeBounds};
+
+use anyhow::{Result, anyhow};
+use serde::Deserialize;
+
+use crate::block::{Block, BlockHeader, Consensus, Transaction};
+
+use super::{
+    BlockChain, DbKeys,
+    store::{ChainStore, Column, IterDirection, StoreIter},
+};
+
+/// Main chain `(height, hash)` pairs over an inclusive height range.
+///
+/// Each end opens its own store iterator on `Column::Heights` the first time it is
+/// polled; the ends stop once they meet.
+pub struct HeightIter<'a> {
+    store: &'a dyn ChainStore,
+    front: u64,
+    back: u64,
+    done: bool,
+    forward: Option<StoreIter<'a>>,
+    reverse: Option<StoreIter<'a>>,
+}
+
+impl HeightIter<'_> {
+    fn step(&mut self, from_back: bool) -> Option<Result<(u64, Vec<u8>)>> {
+        if self.done {
+            return None;
+        }
+        let (slot, start, direction) = if from_back {
+            (&mut self.reverse, self.back, IterDirection::Reverse)
+        } else {
+            (&mut self.forward, self.front, IterDirection::Forward)
+        };
+        let store = self.store;
+        let iter = slot.get_or_insert_with(|| {
+            store.iter_from(Column::Heights, &DbKeys::height_key(start), direction)
+        });
+
+        let item = match iter.next() {
+            Some(Ok((key, hash))) => match DbKeys::height_from_key(&key) {
+                Some(height) if height == start => Ok((height, hash)),
+                _ => Err(anyhow!("Block hash not found at height {}", start)),
+            },
+            Some(Err(e)) => Err(e),
+            None => Err(anyhow!("Block hash not found at height {}", start)),
+        };
+
+        if item.is_err() || self.front == self.back {
+            self.done = true;
+        } else if from_back {
+            self.back -= 1;
+        } else {
+            self.front += 1;
+        }
+        Some(item)
+    }
+}
+
+impl Iterator for HeightIter<'_> {
+    type Item = Result<(u64, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.step(false)
+    }
+}
+
+impl DoubleEndedIterator for HeightIter<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.step(true)
+    }
+}
+
+/// Headers reached by following `prev_hash` links, starting with the given block and
+/// ending with genesis. Works on any branch of the block tree.
+pub struct Ancestors<'a, C: Consensus, S: ChainStore> {
+    chain: &'a BlockChain<C, S>,
+    next: Option<Vec<u8>>,
+}
+
+impl<C: Consensus + for<'a> Deserialize<'a>, S: ChainStore> Iterator for Ancestors<'_, C, S> {
+    type Item = Result<BlockHeader<C::Data>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let hash = self.next.take()?;
+        let item = self.chain.get_header(&hash).and_then(|header| {
+            let meta = self
+                .chain
+                .get_meta(&hash)?
+                .ok_or_else(|| anyhow!("Block {} not in block tree", hex::encode(&hash)))?;
+            if meta.height > 0 {
+                self.next = Some(header.prev_hash.clone());
+            }
+            Ok(header)
+        });
+        Some(item)
+    }
+}
+
+impl<C: Consensus + for<'a> Deserialize<'a>, S: ChainStore> BlockChain<C, S> {
+    /// Main chain hashes in `range`, clamped to the current tip.
+    pub fn iter_hashes(&self, range: impl RangeBounds<u64>) -> Result<HeightIter<'_>> {
+        let tip = self.get_height()?;
+        let front = match range.start_bound() {
+            Bound::Included(&h) => h,
+            Bound::Excluded(&h) => h.saturating_add(1),
+            Bound::Unbounded => 0,
+        };
+        let back = match range.end_bound() {
+            Bound::Included(&h) => Some(h),
+            Bound::Excluded(&h) => h.checked_sub(1),
+            Bound::Unbounded => Some(tip),
+        };
+        let (back, done) = match back.map(|h| h.min(tip)) {
+            Some(back) if front <= back => (back, false),
+            _ => (0, true),
+        };
+
+        Ok(HeightIter {
+            store: &self.store,
+            front,
+            back,
+            done,
+            forward: None,
+            reverse: None,
+        })
+    }
+
+    pub fn iter_headers(
+        &self,
+        range: impl RangeBounds<u64>,
+    ) -> Result<impl DoubleEndedIterator<Item = Result<BlockHeader<C::Data>>> + '_> {
+        Ok(self
+            .iter_hashes(range)?
+            .map(|item| item.and_then(|(_, hash)| self.get_header(&hash))))
+    }
+
+    pub fn iter_blocks<T: Transaction + for<'a> Deserialize<'a>>(
+        &self,
+        range: impl RangeBounds<u64>,
+    ) -> Result<impl DoubleEndedIterator<Item = Result<Block<T, C>>> + '_> {
+        Ok(self
+            .iter_hashes(range)?
+            .map(|item| item.and_then(|(_, hash)| self.get_block_by_hash(&hash))))
+    }
+
+    pub fn ancestors(&self, hash: &[u8]) -> Ancestors<'_, C, S> {
+        Ancestors {
+            chain: self,
+            next: Some(hash.to_vec()),
+        }
+    }
+}
@@ -1,171 +1,655 @@
+pub mod archive;
+pub mod integrity;
+pub mod iter;
+pub mod pos;
 pub mod pow;
+pub mod storage;
+pub mod store;
+pub mod txindex;
 
-use std::path::Path;
+use std::{
+    collections::HashSet,
+    ops::{Deref, DerefMut},
+    path::Path,
+    sync::Arc,
+};
 
-use anyhow::{Result, bail};
-use rocksdb::{DB, Options, WriteBatch};
-use serde::Deserialize;
+use anyhow::{Result, anyhow, bail};
+use chrono::Utc;
+use num_bigint::BigUint;
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    block::{Block, Consensus, Transaction, Transactions},
+    block::{Block, BlockHeader, Consensus, Transaction, Transactions, error::ValidationError},
     hash::Hashable,
 };
 
+use storage::RocksStore;
+use store::{ChainStore, Column, StoreBatch};
+
 pub mod blockchain_control {
     pub const TARGET_TIME_SPAN: u64 = 120;
     pub const DIFFICULTY_ADJUST_INTERVAL: u64 = 10;
     pub const DEFAULT_DIFFICULTY: u32 = 0x1f00_ffff;
+    pub const COINBASE_MATURITY: u64 = 100;
+    /// Blocks, the parent included, whose median timestamp a new block must exceed.
+    pub const MEDIAN_TIME_SPAN: usize = 11;
+    /// Seconds a block timestamp may be ahead of local time.
+    pub const MAX_FUTURE_DRIFT: i64 = 2 * 60 * 60;
+    /// Timestamp of the genesis block.
+    pub const GENESIS_TIMESTAMP: i64 = 1685000000;
 }
 
+/// Keys within each `Column`. `Column::Headers` and `Column::Bodies` are keyed by block hash.
 pub struct DbKeys;
 
 impl DbKeys {
+    // `Column::State`
     pub const LAST_HASH: &'static [u8] = b"last_hash";
     pub const CUR_HEIGHT: &'static [u8] = b"height";
     pub const CUR_STATE: &'static [u8] = b"state";
+    pub const TX_INDEX: &'static [u8] = b"tx_index";
+
+    /// `Column::Heights` key; big-endian so iteration follows height order.
+    pub fn height_key(height: u64) -> [u8; 8] {
+        height.to_be_bytes()
+    }
 
-    pub fn block_key(hash: &[u8]) -> Vec<u8> {
-        format!("block_{}", hex::encode(hash)).into_bytes()
+    pub fn height_from_key(key: &[u8]) -> Option<u64> {
+        key.try_into().ok().map(u64::from_be_bytes)
     }
 
-    pub fn hash_from_block_key(key: &[u8]) -> Option<Vec<u8>> {
-        key.strip_prefix(b"block_").map(|s| s.to_vec())
+    /// `Column::State` key of the consensus state after a block.
+    pub fn state_key(hash: &[u8]) -> Vec<u8> {
+        [b"c".as_slice(), hash].concat()
     }
 
-    pub fn height_key(height: u64) -> Vec<u8> {
-        format!("height_{:016x}", height).into_bytes()
+    /// `Column::Indexes` key of a `BlockMeta`.
+    pub fn meta_key(hash: &[u8]) -> Vec<u8> {
+        [b"m".as_slice(), hash].concat()
     }
+
+    /// `Column::Indexes` key of a `txindex::TxLocation`.
+    pub fn tx_key(txid: &[u8]) -> Vec<u8> {
+        [b"t".as_slice(), txid].concat()
+    }
+}
+
+/// Position of a stored block in the block tree, main chain or not.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockMeta {
+    pub height: u64,
+    pub prev_hash: Vec<u8>,
+    /// Sum of `Consensus::block_weight` from genesis up to and including this block.
+    pub chain_weight: BigUint,
 }
 
-pub struct BlockChain<C: Consensus> {
-    db: DB,
+/// A switch of the main chain to a heavier branch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reorg {
+    pub old_tip: Vec<u8>,
+    pub new_tip: Vec<u8>,
+    /// Blocks removed from the main chain, old tip first.
+    pub disconnected: Vec<Vec<u8>>,
+    /// Blocks added to the main chain, child of the fork point first.
+    pub connected: Vec<Vec<u8>>,
+}
+
+/// Outcome of `BlockChain::add_block`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChainUpdate {
+    /// The block extended the current tip.
+    Extended { hash: Vec<u8>, height: u64 },
+    /// The block was stored on a branch that is not heavier than the main chain.
+    SideChain { hash: Vec<u8>, height: u64 },
+    /// The block made its branch the heaviest and the main chain was switched.
+    Reorganized(Reorg),
+}
+
+pub struct BlockChain<C: Consensus, S: ChainStore = RocksStore> {
+    store: S,
     cs: C,
+    tx_index: bool,
+    clock: Arc<dyn Fn() -> i64 + Send + Sync>,
+    max_future_drift: i64,
 }
 
 impl<C: Consensus + for<'a> Deserialize<'a>> BlockChain<C> {
-    pub fn new<T: Transaction + Default>(path: impl AsRef<Path>) -> Result<Self> {
-        let mut opts = Options::default();
-        opts.create_if_missing(true);
-        opts.set_compression_type(rocksdb::DBCompressionType::Zstd);
-        opts.set_max_open_files(512);
-
+    /// Opens or creates a RocksDB backed chain, migrating the flat key layout if found.
+    pub fn new<T: Transaction + Default + for<'a> Deserialize<'a>>(
+        path: impl AsRef<Path>,
+    ) -> Result<Self> {
         log::info!("Opened db at {:?}", path.as_ref().display());
-        let db = DB::open(&opts, path)?;
+        let store = RocksStore::open(path)?;
+        storage::migrate_flat_keys::<T, C>(&store)?;
+        Self::with_store::<T>(store)
+    }
+}
 
-        let cur_state = match db.get(DbKeys::CUR_STATE)? {
+impl<C: Consensus + for<'a> Deserialize<'a>, S: ChainStore> BlockChain<C, S> {
+    /// Loads a chain from `store`, writing the genesis block if it is empty.
+    pub fn with_store<T: Transaction + Default + for<'a> Deserialize<'a>>(
+        store: S,
+    ) -> Result<Self> {
+        let cur_state = match store.get(Column::State, DbKeys::CUR_STATE)? {
             Some(state) => bincode::deserialize(&state)?,
             None => C::default(),
         };
 
-        if db.get(DbKeys::height_key(0))?.is_none() {
+        let tx_index = store
+            .get(Column::State, DbKeys::TX_INDEX)?
+            .is_some_and(|v| v == [1]);
+
+        let mut chain = Self {
+            store,
+            cs: cur_state,
+            tx_index,
+            clock: Arc::new(|| Utc::now().timestamp()),
+            max_future_drift: blockchain_control::MAX_FUTURE_DRIFT,
+        };
+        if chain.get_hash(0)?.is_none() {
             log::info!("No last hash, Creating genesis block");
             let genesis: Block<T, C> = Block::<T, C>::genesis();
+            chain.cs.apply_block(0, &genesis);
             let hash = genesis.header.hash();
-            
-            let mut batch = WriteBatch::default();
-            batch.put(DbKeys::block_key(&hash), bincode::serialize(&genesis)?);
-            batch.put(DbKeys::LAST_HASH, &hash);
-            batch.put(DbKeys::height_key(0), &hash);
-            batch.put(DbKeys::CUR_HEIGHT, &0u64.to_le_bytes());
-            batch.put(DbKeys::CUR_STATE, bincode::serialize(&cur_state)?);
-            db.write(batch)?;
+            let meta = BlockMeta {
+                height: 0,
+                prev_hash: genesis.header.prev_hash.clone(),
+                chain_weight: chain.cs.block_weight(&genesis.header),
+            };
+
+            let mut batch = StoreBatch::default();
+            chain.put_block(&mut batch, &hash, &genesis, &meta)?;
+            batch.put(Column::Heights, DbKeys::height_key(0), hash);
+            batch.put(Column::State, DbKeys::LAST_HASH, hash);
+            batch.put(Column::State, DbKeys::CUR_HEIGHT, 0u64.to_le_bytes());
+            let state = bincode::serialize(&chain.cs)?;
+            batch.put(Column::State, DbKeys::state_key(&hash), &state);
+            batch.put(Column::State, DbKeys::CUR_STATE, state);
+            chain.store.write(batch)?;
+        }
+
+        chain.index_main_chain::<T>()?;
+        Ok(chain)
+    }
+
+    /// Writes missing `BlockMeta` entries and state snapshots for databases created
+    /// before the block tree, replaying `Consensus::apply_block` over the main chain.
+    /// Such databases hold no per-block state, so the replay starts from the stored
+    /// current state, which `storage::migrate_flat_keys` has reduced to its
+    /// configuration. The replayed tip state becomes the current state.
+    fn index_main_chain<T: Transaction + for<'a> Deserialize<'a>>(&mut self) -> Result<()> {
+        let tip = self.get_tip_hash()?;
+        if self.get_meta(&tip)?.is_some() {
+            return Ok(());
         }
 
-        Ok(Self { db, cs: cur_state })
+        log::info!("Indexing main chain into block tree");
+        let mut batch = StoreBatch::default();
+        let mut state = self.cs.clone();
+        let mut chain_weight = BigUint::default();
+        for height in 0..=self.get_height()? {
+            let block: Block<T, C> = self.get_block(height)?;
+            let hash = block.header.hash();
+            chain_weight += state.block_weight(&block.header);
+            state.apply_block(height, &block);
+            let meta = BlockMeta {
+                height,
+                prev_hash: block.header.prev_hash.clone(),
+                chain_weight: chain_weight.clone(),
+            };
+            batch.put(
+                Column::Indexes,
+                DbKeys::meta_key(&hash),
+                bincode::serialize(&meta)?,
+            );
+            batch.put(
+                Column::State,
+                DbKeys::state_key(&hash),
+                bincode::serialize(&state)?,
+            );
+        }
+        batch.put(
+            Column::State,
+            DbKeys::CUR_STATE,
+            bincode::serialize(&state)?,
+        );
+        self.store.write(batch)?;
+        self.cs = state;
+        Ok(())
+    }
+
+    fn put_block<T: Transaction>(
+        &self,
+        batch: &mut StoreBatch,
+        hash: &[u8],
+        block: &Block<T, C>,
+        meta: &BlockMeta,
+    ) -> Result<()> {
+        batch.put(Column::Headers, hash, bincode::serialize(&block.header)?);
+        batch.put(
+            Column::Bodies,
+            hash,
+            bincode::serialize(block.transactions())?,
+        );
+        batch.put(
+            Column::Indexes,
+            DbKeys::meta_key(hash),
+            bincode::serialize(meta)?,
+        );
+        Ok(())
+    }
+
+    /// Replaces the source of local time, in unix seconds, used by the future drift rule.
+    pub fn with_clock(mut self, clock: impl Fn() -> i64 + Send + Sync + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    pub fn with_max_future_drift(mut self, seconds: i64) -> Self {
+        self.max_future_drift = seconds;
+        self
+    }
+
+    /// Local time in unix seconds, as given by the clock.
+    pub fn local_time(&self) -> i64 {
+        (self.clock)()
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
     }
 
     pub fn get_consensus(&self) -> &C {
         &self.cs
     }
 
-    pub fn get_consensus_mut(&mut self) -> &mut C {
-        &mut self.cs
+    /// Mutable access to the consensus state of the tip. Blocks are validated against
+    /// the stored state of their parent, so changes are stored by `put_state` once the
+    /// returned guard is dropped.
+    pub fn get_consensus_mut(&mut self) -> ConsensusMut<'_, C, S> {
+        ConsensusMut { chain: self }
     }
 
-    pub fn add_block<
-        T: Transaction + for<'a> Deserialize<'a>,
-    >(
+    /// Stores a block anywhere in the block tree whose parent is known.
+    ///
+    /// The main chain follows the branch with the greatest cumulative weight; ties keep
+    /// the branch seen first. Switching branches rewrites the height index and tip in
+    /// a single batch.
+    ///
+    /// Each block is validated against, and stored with, the consensus state of its
+    /// parent advanced by `Consensus::apply_block`. Whenever the tip moves the state
+    /// of the new tip becomes the current state.
+    ///
+    /// Rejected blocks fail with a `ValidationError`, recoverable through
+    /// `anyhow::Error::downcast_ref`.
+    pub fn add_block<T: Transaction + for<'a> Deserialize<'a>>(
         &mut self,
         block: Block<T, C>,
-    ) -> Result<()> {
-        self.validate_new(&block)?;
-        let mut batch = WriteBatch::default();
-        let block_hash = block.header.hash();
+    ) -> Result<ChainUpdate> {
+        let block_hash = block.header.hash().to_vec();
+        if self.get_meta(&block_hash)?.is_some() {
+            bail!("Block {} already known", hex::encode(&block_hash));
+        }
+        let parent_meta = self
+            .get_meta(&block.header.prev_hash)?
+            .ok_or(ValidationError::UnknownParent)?;
+        let parent: Block<T, C> = self.get_block_by_hash(&block.header.prev_hash)?;
+        let tip_hash = self.get_tip_hash()?;
+        let mut state = self.parent_state::<T>(&block.header.prev_hash)?;
+        Self::validate_new(&state, &block, &parent)?;
+        self.check_timestamp(&block.header)?;
+        state.validate_time(&block.header, self.local_time())?;
+        self.check_coinbase_maturity(&state, &block)?;
+
+        let meta = BlockMeta {
+            height: parent_meta.height + 1,
+            prev_hash: block.header.prev_hash.clone(),
+            chain_weight: parent_meta.chain_weight + state.block_weight(&block.header),
+        };
+        state.apply_block(meta.height, &block);
+
+        let mut batch = StoreBatch::default();
+        self.put_block(&mut batch, &block_hash, &block, &meta)?;
+        let state_raw = bincode::serialize(&state)?;
+        batch.put(Column::State, DbKeys::state_key(&block_hash), &state_raw);
 
-        batch.put(DbKeys::block_key(&block_hash), bincode::serialize(&block)?);
-        batch.put(DbKeys::LAST_HASH, &block_hash);
+        let tip_meta = self
+            .get_meta(&tip_hash)?
+            .ok_or_else(|| anyhow!("Tip block not found!"))?;
 
-        let new_height = self.get_height()? + 1;
-        batch.put(DbKeys::height_key(new_height), &block_hash);
-        batch.put(DbKeys::CUR_HEIGHT, &new_height.to_le_bytes());
+        let update = if block.header.prev_hash == tip_hash {
+            batch.put(
+                Column::Heights,
+                DbKeys::height_key(meta.height),
+                &block_hash,
+            );
+            if self.tx_index {
+                self.index_transactions(&mut batch, &block_hash, meta.height, &block)?;
+            }
+            ChainUpdate::Extended {
+                hash: block_hash.clone(),
+                height: meta.height,
+            }
+        } else if meta.chain_weight > tip_meta.chain_weight {
+            let reorg = self.plan_reorg(&tip_hash, &tip_meta, &block_hash, &meta)?;
+            let fork_height = meta.height - reorg.connected.len() as u64;
+            for (i, hash) in reorg.connected.iter().enumerate() {
+                batch.put(
+                    Column::Heights,
+                    DbKeys::height_key(fork_height + 1 + i as u64),
+                    hash,
+                );
+            }
+            for height in meta.height + 1..=tip_meta.height {
+                batch.delete(Column::Heights, DbKeys::height_key(height));
+            }
+            if self.tx_index {
+                self.reindex_reorg(&mut batch, &reorg, fork_height, &block)?;
+            }
+            log::info!(
+                "Reorganizing at height {}: {} blocks disconnected, {} connected",
+                fork_height,
+                reorg.disconnected.len(),
+                reorg.connected.len()
+            );
+            ChainUpdate::Reorganized(reorg)
+        } else {
+            self.store.write(batch)?;
+            return Ok(ChainUpdate::SideChain {
+                hash: block_hash,
+                height: meta.height,
+            });
+        };
 
-        self.db.write(batch)?;
+        batch.put(Column::State, DbKeys::LAST_HASH, &block_hash);
+        batch.put(Column::State, DbKeys::CUR_HEIGHT, meta.height.to_le_bytes());
+        batch.put(Column::State, DbKeys::CUR_STATE, state_raw);
+        self.store.write(batch)?;
+        self.cs = state;
+        Ok(update)
+    }
+
+    /// Walks the new branch back to the main chain. `new_hash` is not stored yet.
+    fn plan_reorg(
+        &self,
+        tip_hash: &[u8],
+        tip_meta: &BlockMeta,
+        new_hash: &[u8],
+        new_meta: &BlockMeta,
+    ) -> Result<Reorg> {
+        let mut connected = vec![new_hash.to_vec()];
+        let mut hash = new_meta.prev_hash.clone();
+        let mut height = new_meta.height - 1;
+        while self.get_hash(height)?.as_deref() != Some(hash.as_slice()) {
+            let meta = self
+                .get_meta(&hash)?
+                .ok_or_else(|| anyhow!("Broken branch at {}", hex::encode(&hash)))?;
+            connected.push(hash);
+            hash = meta.prev_hash;
+            height -= 1;
+        }
+        connected.reverse();
+
+        let disconnected = (height + 1..=tip_meta.height)
+            .rev()
+            .map(|h| {
+                self.get_hash(h)?
+                    .ok_or_else(|| anyhow!("Block hash not found at height {}", h))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Reorg {
+            old_tip: tip_hash.to_vec(),
+            new_tip: new_hash.to_vec(),
+            disconnected,
+            connected,
+        })
+    }
+
+    /// Runs structural, consensus and transaction checks, in that order.
+    fn validate_new<T: Transaction + for<'a> Deserialize<'a>>(
+        state: &C,
+        block: &Block<T, C>,
+        parent: &Block<T, C>,
+    ) -> Result<(), ValidationError> {
+        block.validate(parent)?;
+        state.validate(block)?;
+        block.verify_transactions()
+    }
+
+    /// Requires the timestamp to exceed the median time past of the parent and to be
+    /// at most `max_future_drift` seconds ahead of local time.
+    fn check_timestamp(&self, header: &BlockHeader<C::Data>) -> Result<()> {
+        self.check_median_time(header)?;
+        let max = self.local_time().saturating_add(self.max_future_drift);
+        if header.timestamp > max {
+            return Err(ValidationError::TimeTooNew {
+                max,
+                timestamp: header.timestamp,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Requires the timestamp to exceed the median time past of the parent.
+    fn check_median_time(&self, header: &BlockHeader<C::Data>) -> Result<()> {
+        let median_time_past = self.median_time_of(&header.prev_hash)?;
+        if header.timestamp <= median_time_past {
+            return Err(ValidationError::TimeTooOld {
+                median_time_past,
+                timestamp: header.timestamp,
+            }
+            .into());
+        }
         Ok(())
     }
 
-    fn validate_new<
-        T: Transaction + for<'a> Deserialize<'a>,
-    >(
+    /// Median timestamp of the last `MEDIAN_TIME_SPAN` main chain blocks, tip included.
+    /// A new block must be timestamped after it.
+    pub fn median_time_past(&self) -> Result<i64> {
+        self.median_time_of(&self.get_tip_hash()?)
+    }
+
+    /// Median time past of the branch ending at `hash`.
+    fn median_time_of(&self, hash: &[u8]) -> Result<i64> {
+        let mut times = self
+            .ancestors(hash)
+            .take(blockchain_control::MEDIAN_TIME_SPAN)
+            .map(|header| header.map(|header| header.timestamp))
+            .collect::<Result<Vec<_>>>()?;
+        times.sort_unstable();
+        Ok(times[times.len() / 2])
+    }
+
+    /// Rejects spends of coinbases from the last `Consensus::coinbase_maturity` blocks
+    /// of the block's own branch, itself included.
+    fn check_coinbase_maturity<T: Transaction + for<'a> Deserialize<'a>>(
         &self,
+        state: &C,
         block: &Block<T, C>,
     ) -> Result<()> {
-        if block.validate(&self.get_last_block()?) {
-            Ok(())
-        } else {
-            bail!("Invalid block!");
+        let maturity = state.coinbase_maturity();
+        let txs = &block.transactions().0;
+        if maturity == 0 || txs.iter().all(|tx| tx.spent_txids().is_empty()) {
+            return Ok(());
+        }
+
+        let mut immature: HashSet<[u8; 32]> =
+            block.coinbase().map(|tx| tx.hash()).into_iter().collect();
+        let mut hash = block.header.prev_hash.clone();
+        for _ in 1..maturity {
+            let Some(meta) = self.get_meta(&hash)? else {
+                break;
+            };
+            let ancestor: Block<T, C> = self.get_block_by_hash(&hash)?;
+            immature.extend(ancestor.coinbase().map(|tx| tx.hash()));
+            if meta.height == 0 {
+                break;
+            }
+            hash = meta.prev_hash;
+        }
+
+        match txs
+            .iter()
+            .position(|tx| tx.spent_txids().iter().any(|txid| immature.contains(txid)))
+        {
+            Some(index) => Err(ValidationError::ImmatureCoinbaseSpend { index }.into()),
+            None => Ok(()),
         }
     }
 
-    pub fn get_block<
-        T: Transaction + for<'a> Deserialize<'a>,
-    >(
+    pub fn get_block<T: Transaction + for<'a> Deserialize<'a>>(
         &self,
         height: u64,
     ) -> Result<Block<T, C>> {
         let block_hash = self
-            .db
-            .get(DbKeys::height_key(height))?
-            .ok_or_else(|| anyhow::anyhow!("Block hash not found at height {}", height))?;
-        let block_raw = self
-            .db
-            .get(DbKeys::block_key(&block_hash))?
-            .ok_or_else(|| anyhow::anyhow!("Block not found for given hash!"))?;
-        Ok(bincode::deserialize(&block_raw)?)
-    }
-
-    pub fn get_last_block<
-        T: Transaction + for<'a> Deserialize<'a>,
-    >(
+            .get_hash(height)?
+            .ok_or_else(|| anyhow!("Block hash not found at height {}", height))?;
+        self.get_block_by_hash(&block_hash)
+    }
+
+    /// Looks up a block on any branch of the block tree.
+    pub fn get_block_by_hash<T: Transaction + for<'a> Deserialize<'a>>(
         &self,
+        hash: &[u8],
     ) -> Result<Block<T, C>> {
+        let header = self.get_header(hash)?;
+        let body_raw = self
+            .store
+            .get(Column::Bodies, hash)?
+            .ok_or_else(|| anyhow!("Block body not found for given hash!"))?;
+        let txs: Transactions<T> = bincode::deserialize(&body_raw)?;
+        Ok(Block::from_parts(header, txs))
+    }
+
+    pub fn get_header(&self, hash: &[u8]) -> Result<BlockHeader<C::Data>> {
+        let header_raw = self
+            .store
+            .get(Column::Headers, hash)?
+            .ok_or_else(|| anyhow!("Block not found for given hash!"))?;
+        Ok(bincode::deserialize(&header_raw)?)
+    }
+
+    pub fn get_last_block<T: Transaction + for<'a> Deserialize<'a>>(&self) -> Result<Block<T, C>> {
         self.get_block(self.get_height()?)
     }
 
+    /// Main chain block hash at `height`.
+    pub fn get_hash(&self, height: u64) -> Result<Option<Vec<u8>>> {
+        self.store.get(Column::Heights, &DbKeys::height_key(height))
+    }
+
+    pub fn get_tip_hash(&self) -> Result<Vec<u8>> {
+        self.store
+            .get(Column::State, DbKeys::LAST_HASH)?
+            .ok_or_else(|| anyhow!("Last hash not found"))
+    }
+
+    pub fn get_meta(&self, hash: &[u8]) -> Result<Option<BlockMeta>> {
+        match self.store.get(Column::Indexes, &DbKeys::meta_key(hash))? {
+            Some(raw) => Ok(Some(bincode::deserialize(&raw)?)),
+            None => Ok(None),
+        }
+    }
+
     pub fn get_height(&self) -> Result<u64> {
-        self.db
-            .get(DbKeys::CUR_HEIGHT)?
+        self.store
+            .get(Column::State, DbKeys::CUR_HEIGHT)?
             .map(|v| u64::from_le_bytes(v[..8].try_into().unwrap()))
-            .or_else(|| Some(0))
-            .ok_or_else(|| anyhow::anyhow!("Blockchain height not found"))
+            .or(Some(0))
+            .ok_or_else(|| anyhow!("Blockchain height not found"))
+    }
+
+    /// Persists the in-memory consensus state as the state of the tip.
+    pub fn put_state(&self) -> Result<()> {
+        let state = bincode::serialize(&self.cs)?;
+        let mut batch = StoreBatch::default();
+        batch.put(
+            Column::State,
+            DbKeys::state_key(&self.get_tip_hash()?),
+            &state,
+        );
+        batch.put(Column::State, DbKeys::CUR_STATE, state);
+        self.store.write(batch)
+    }
+
+    /// Consensus state of the tip as last persisted.
+    pub fn get_state(&self) -> Result<C> {
+        match self.store.get(Column::State, DbKeys::CUR_STATE)? {
+            Some(raw) => Ok(bincode::deserialize(&raw)?),
+            None => bail!("Can't found state for consensus!"),
+        }
     }
 
-    pub fn put_state(&self, chain: &BlockChain<C>) -> Result<()> {
-        chain
-            .db
-            .put(DbKeys::CUR_STATE, bincode::serialize(&self.cs)?)
-            .map_err(|e| anyhow::anyhow!(e))
+    /// Consensus state after the main chain block at `height`. `None` for blocks stored
+    /// before snapshots were recorded.
+    pub fn state_at(&self, height: u64) -> Result<Option<C>> {
+        let hash = self
+            .get_hash(height)?
+            .ok_or_else(|| anyhow!("Block hash not found at height {}", height))?;
+        self.state_of(&hash)
+    }
+
+    /// Consensus state after a block on any branch of the block tree.
+    pub fn state_of(&self, hash: &[u8]) -> Result<Option<C>> {
+        match self.store.get(Column::State, &DbKeys::state_key(hash))? {
+            Some(raw) => Ok(Some(bincode::deserialize(&raw)?)),
+            None => Ok(None),
+        }
     }
 
-    pub fn get_state(&self) -> Result<C::Data> {
-        let state = self.db.get(DbKeys::CUR_STATE)?;
-        match state {
-            Some(s) => {
-                let s = bincode::deserialize(&s).map_err(|e| anyhow::anyhow!(e))?;
-                Ok(s)
+    /// State a child of `hash` builds on. For blocks stored without a snapshot it is
+    /// rebuilt by replaying `Consensus::apply_block` from the nearest ancestor that
+    /// has one; genesis always does.
+    fn parent_state<T: Transaction + for<'a> Deserialize<'a>>(&self, hash: &[u8]) -> Result<C> {
+        let mut unapplied = Vec::new();
+        let mut cursor = hash.to_vec();
+        let mut state = loop {
+            if let Some(state) = self.state_of(&cursor)? {
+                break state;
             }
-            None => {
-                bail!("Can't found state for consensus!")
+            let meta = self
+                .get_meta(&cursor)?
+                .ok_or_else(|| anyhow!("Block {} not in block tree", hex::encode(&cursor)))?;
+            if meta.height == 0 {
+                bail!("Genesis state snapshot not found");
             }
+            unapplied.push((meta.height, std::mem::replace(&mut cursor, meta.prev_hash)));
+        };
+        for (height, hash) in unapplied.into_iter().rev() {
+            let block: Block<T, C> = self.get_block_by_hash(&hash)?;
+            state.apply_block(height, &block);
+        }
+        Ok(state)
+    }
+}
+
+/// Consensus state of the tip borrowed by `BlockChain::get_consensus_mut`, stored as
+/// the tip's snapshot and the current state when dropped.
+pub struct ConsensusMut<'a, C: Consensus + for<'de> Deserialize<'de>, S: ChainStore> {
+    chain: &'a mut BlockChain<C, S>,
+}
+
+impl<C: Consensus + for<'de> Deserialize<'de>, S: ChainStore> Deref for ConsensusMut<'_, C, S> {
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        &self.chain.cs
+    }
+}
+
+impl<C: Consensus + for<'de> Deserialize<'de>, S: ChainStore> DerefMut for ConsensusMut<'_, C, S> {
+    fn deref_mut(&mut self) -> &mut C {
+        &mut self.chain.cs
+    }
+}
+
+impl<C: Consensus + for<'de> Deserialize<'de>, S: ChainStore> Drop for ConsensusMut<'_, C, S> {
+    fn drop(&mut self) {
+        if let Err(e) = self.chain.put_state() {
+            log::error!("Failed to store consensus state: {}", e);
         }
     }
 }
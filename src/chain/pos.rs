@@ -0,0 +1,48 @@
+use anyhow::{Result, anyhow, bail};
+use ed25519_dalek::VerifyingKey;
+
+use crate::block::pos::{Election, PoS};
+use crate::chain::{BlockChain, store::ChainStore};
+
+impl<S: ChainStore> BlockChain<PoS, S> {
+    /// Validator entitled to propose the main chain block at `height`, from the hash
+    /// and state of its parent. Heights up to one past the tip are known; `None` if
+    /// no validator holds the minimum stake. Fails under `Election::Vrf`, where only
+    /// the leaders themselves know.
+    pub fn expected_proposer(&self, height: u64) -> Result<Option<VerifyingKey>> {
+        if let Election::Vrf { .. } = self.cs.election {
+            bail!("Proposers are private under VRF election");
+        }
+        let tip = self.get_height()?;
+        if height == 0 || height > tip + 1 {
+            bail!("No expected proposer for height {}", height);
+        }
+        let prev_hash = self
+            .get_hash(height - 1)?
+            .ok_or_else(|| anyhow!("Block hash not found at height {}", height - 1))?;
+        if height == tip + 1 {
+            return Ok(self.cs.select_proposer(&prev_hash, height));
+        }
+        match self.state_at(height - 1)? {
+            Some(state) => Ok(state.select_proposer(&prev_hash, height)),
+            None => bail!("No consensus state recorded at height {}", height - 1),
+        }
+    }
+
+    /// Active validator set of `epoch`, as recomputed after the last main chain block
+    /// of the epoch before and recorded in that block's state. Epoch 0 uses the set
+    /// of the genesis state.
+    pub fn validators_at(&self, epoch: u64) -> Result<Vec<(VerifyingKey, u64)>> {
+        let height = epoch
+            .checked_mul(self.cs.epoch_length.max(1))
+            .ok_or_else(|| anyhow!("Epoch {} out of range", epoch))?
+            .saturating_sub(1);
+        if height > self.get_height()? {
+            bail!("Epoch {} has not started", epoch);
+        }
+        match self.state_at(height)? {
+            Some(state) => Ok(state.active_validators),
+            None => bail!("No consensus state recorded at height {}", height),
+        }
+    }
+}
@@ -1,30 +1,180 @@
-use anyhow::Result;
-use serde::Deserialize;
+use anyhow::{Result, anyhow, bail};
+use num_bigint::BigUint;
+use serde::{Deserialize, Serialize};
 
-use crate::block::{Block, Transaction, pow::PoW};
-use crate::chain::{BlockChain, DbKeys, blockchain_control};
-use crate::hash::{bits_to_target, target_to_bits};
+use crate::block::{
+    Transaction,
+    pow::{PoW, PoWData},
+};
+use crate::chain::{BlockChain, ChainUpdate, store::ChainStore};
+use crate::mining::template::{BlockTemplate, Work};
 
-impl BlockChain<PoW> {
-    pub fn adjust_difficulty<T: Transaction + for<'a> Deserialize<'a>>(&mut self) -> Result<u32> {
-        let height = self.get_height()?;
-        if height % self.cs.difficulty_adjust_interval != 0 || height == 0 {
+/// Main chain mining figures for dashboards, from `BlockChain::network_stats`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NetworkStats {
+    pub height: u64,
+    /// Bits required of the next block.
+    pub bits: u32,
+    /// Expected hashes to find the next block.
+    pub next_block_work: BigUint,
+    pub chain_work: BigUint,
+    /// Blocks the figures below are averaged over.
+    pub window: u64,
+    /// Mean seconds between blocks.
+    pub average_block_time: u64,
+    /// Estimated hashes per second of the whole network.
+    pub hashrate: BigUint,
+}
+
+impl<S: ChainStore> BlockChain<PoW, S> {
+    /// Bits a main chain block at `height` must carry, from the state of its parent.
+    /// Heights up to one past the tip are known.
+    pub fn expected_bits(&self, height: u64) -> Result<u32> {
+        let tip = self.get_height()?;
+        if height == 0 || height > tip + 1 {
+            bail!("No expected bits for height {}", height);
+        }
+        if height == tip + 1 {
             return Ok(self.cs.cur_bits);
         }
-        let first_block: Block<T, PoW> =
-            self.get_block(height - blockchain_control::DIFFICULTY_ADJUST_INTERVAL)?;
-        let last_block: Block<T, PoW> = self.get_last_block()?;
+        match self.state_at(height - 1)? {
+            Some(state) => Ok(state.cur_bits),
+            None => bail!("No consensus state recorded at height {}", height - 1),
+        }
+    }
+
+    /// Total work of the main chain from genesis up to and including `height`. This is
+    /// the `BlockMeta::chain_weight` fork choice compares, with `PoWData::work` per block.
+    pub fn chain_work(&self, height: u64) -> Result<BigUint> {
+        let hash = self
+            .get_hash(height)?
+            .ok_or_else(|| anyhow!("Block hash not found at height {}", height))?;
+        self.work_of(&hash)
+    }
+
+    pub fn tip_work(&self) -> Result<BigUint> {
+        self.work_of(&self.get_tip_hash()?)
+    }
+
+    /// Estimated network hashes per second over the last `window` blocks: the work they
+    /// add divided by the time they took. The window is capped at the chain height.
+    pub fn network_hashrate(&self, window: u64) -> Result<BigUint> {
+        Ok(self.network_stats(window)?.hashrate)
+    }
+
+    pub fn network_stats(&self, window: u64) -> Result<NetworkStats> {
+        let (window, span) = self.window_span(window)?;
+        let height = self.get_height()?;
+        let chain_work = self.chain_work(height)?;
+        let hashrate = (chain_work.clone() - self.chain_work(height - window)?) / span;
+        Ok(NetworkStats {
+            height,
+            bits: self.cs.cur_bits,
+            next_block_work: PoWData {
+                bits: self.cs.cur_bits,
+                nonce: 0,
+            }
+            .work(),
+            chain_work,
+            window,
+            average_block_time: span / window,
+            hashrate,
+        })
+    }
+
+    /// Clamped window and the seconds it spans, at least 1.
+    fn window_span(&self, window: u64) -> Result<(u64, u64)> {
+        let tip = self.get_height()?;
+        let window = window.min(tip);
+        if window == 0 {
+            bail!("No blocks to estimate the hashrate from");
+        }
+        let mut headers = self.iter_headers(tip - window..=tip)?;
+        let first = headers
+            .next()
+            .ok_or_else(|| anyhow!("Block not found at height {}", tip - window))??;
+        let last = headers
+            .next_back()
+            .ok_or_else(|| anyhow!("Block not found at height {}", tip))??;
+        Ok((window, (last.timestamp - first.timestamp).max(1) as u64))
+    }
 
-        let actual_span = (last_block.header.timestamp - first_block.header.timestamp).max(1);
+    fn work_of(&self, hash: &[u8]) -> Result<BigUint> {
+        match self.get_meta(hash)? {
+            Some(meta) => Ok(meta.chain_weight),
+            None => bail!("Block {} not in block tree", hex::encode(hash)),
+        }
+    }
 
-        let prev_target = bits_to_target(first_block.header.data.bits);
-        let new_target =
-            prev_target.clone() * blockchain_control::TARGET_TIME_SPAN / actual_span as u64;
-        let new_target = new_target.clamp(prev_target.clone() / 4u32, prev_target.clone() * 4u32);
-        let new_bits = target_to_bits(new_target);
+    /// Template for a block on top of the current tip. Coinbases and transactions that
+    /// fail `Transaction::verify` are left out of `txs`.
+    pub fn block_template<T: Transaction + Clone + for<'a> Deserialize<'a>>(
+        &self,
+        coinbase: Option<T>,
+        txs: Vec<T>,
+    ) -> Result<BlockTemplate<T>> {
+        let mut template = self.unfinished_template(txs)?;
+        template.coinbase = coinbase;
+        template.merkle_root = template
+            .block_transactions()
+            .merkle_root()
+            .ok_or_else(|| anyhow!("No merkle root found!"))?;
+        Ok(template)
+    }
+
+    /// Like `block_template`, with the coinbase built from the template's height and
+    /// reward.
+    pub fn block_template_with<T: Transaction + Clone + for<'a> Deserialize<'a>>(
+        &self,
+        coinbase: impl FnOnce(&BlockTemplate<T>) -> T,
+        txs: Vec<T>,
+    ) -> Result<BlockTemplate<T>> {
+        let template = self.unfinished_template(txs)?;
+        let coinbase = coinbase(&template);
+        template.with_coinbase(coinbase)
+    }
+
+    /// Template without a coinbase or merkle root.
+    fn unfinished_template<T: Transaction + Clone + for<'a> Deserialize<'a>>(
+        &self,
+        txs: Vec<T>,
+    ) -> Result<BlockTemplate<T>> {
+        let transactions: Vec<T> = txs
+            .into_iter()
+            .filter(|tx| tx.coinbase_amount().is_none() && tx.verify())
+            .collect();
+        let height = self.get_height()? + 1;
+        let Some(reward) = transactions
+            .iter()
+            .try_fold(self.cs.subsidy(height), |sum, tx| sum.checked_add(tx.fee()))
+        else {
+            bail!("Transaction fees overflow the block reward");
+        };
+        let target = PoWData {
+            bits: self.cs.cur_bits,
+            nonce: 0,
+        }
+        .target_bytes();
+        Ok(BlockTemplate {
+            height,
+            prev_hash: self.get_tip_hash()?,
+            merkle_root: Vec::new(),
+            timestamp: self.local_time().max(self.median_time_past()? + 1),
+            bits: self.cs.cur_bits,
+            target: target.to_vec(),
+            algorithm: self.cs.pow_hash,
+            reward,
+            coinbase: None,
+            transactions,
+        })
+    }
 
-        self.db.put(DbKeys::CUR_STATE, &new_bits.to_le_bytes())?;
-        self.cs.cur_bits = new_bits;
-        Ok(new_bits)
+    /// Completes `template` with a miner's solution and adds the block.
+    pub fn submit_work<T: Transaction + Clone + for<'a> Deserialize<'a>>(
+        &mut self,
+        template: BlockTemplate<T>,
+        work: Work,
+    ) -> Result<ChainUpdate> {
+        self.add_block(template.into_block(work))
     }
 }
@@ -0,0 +1,197 @@
+use std::path::Path;
+
+use anyhow::{Result, anyhow, bail};
+use rocksdb::{
+    BlockBasedOptions, Cache, ColumnFamily, ColumnFamilyDescriptor, DB, DBCompressionType,
+    Direction, IteratorMode, Options, WriteBatch,
+};
+use serde::Deserialize;
+
+use crate::block::{Block, Consensus, Transaction};
+
+use super::{
+    DbKeys,
+    store::{BatchOp, ChainStore, Column, IterDirection, StoreBatch, StoreIter},
+};
+
+/// Column family names.
+pub mod cf {
+    pub const HEADERS: &str = "headers";
+    pub const BODIES: &str = "bodies";
+    pub const HEIGHTS: &str = "heights";
+    pub const STATE: &str = "state";
+    pub const INDEXES: &str = "indexes";
+
+    pub const ALL: [&str; 5] = [HEADERS, BODIES, HEIGHTS, STATE, INDEXES];
+}
+
+fn cf_name(col: Column) -> &'static str {
+    match col {
+        Column::Headers => cf::HEADERS,
+        Column::Bodies => cf::BODIES,
+        Column::Heights => cf::HEIGHTS,
+        Column::State => cf::STATE,
+        Column::Indexes => cf::INDEXES,
+    }
+}
+
+const MB: usize = 1024 * 1024;
+
+fn cf_options(name: &str) -> Options {
+    // (compression, block cache, bloom filter)
+    let (compression, cache_size, bloom) = match name {
+        // Small, hot and looked up by hash.
+        cf::HEADERS => (DBCompressionType::Lz4, 32 * MB, true),
+        // Large and rarely reread; trade CPU for space.
+        cf::BODIES => (DBCompressionType::Zstd, 16 * MB, true),
+        // Fixed size keys and hashes compress poorly and are read in ranges.
+        cf::HEIGHTS => (DBCompressionType::None, 8 * MB, false),
+        cf::STATE => (DBCompressionType::None, 4 * MB, false),
+        cf::INDEXES => (DBCompressionType::Lz4, 32 * MB, true),
+        _ => (DBCompressionType::Zstd, 8 * MB, false),
+    };
+
+    let mut table = BlockBasedOptions::default();
+    table.set_block_cache(&Cache::new_lru_cache(cache_size));
+    if bloom {
+        table.set_bloom_filter(10.0, false);
+    }
+
+    let mut opts = Options::default();
+    opts.set_compression_type(compression);
+    opts.set_block_based_table_factory(&table);
+    opts
+}
+
+/// `ChainStore` backed by RocksDB, one column family per `Column`.
+pub struct RocksStore {
+    db: DB,
+}
+
+impl RocksStore {
+    /// Opens the database with every column family, creating missing ones.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+        opts.set_compression_type(DBCompressionType::Zstd);
+        opts.set_max_open_files(512);
+
+        let descriptors = cf::ALL
+            .iter()
+            .map(|name| ColumnFamilyDescriptor::new(*name, cf_options(name)));
+        let db = DB::open_cf_descriptors(&opts, path, descriptors)?;
+        Ok(Self { db })
+    }
+
+    fn handle(&self, col: Column) -> Result<&ColumnFamily> {
+        self.db
+            .cf_handle(cf_name(col))
+            .ok_or_else(|| anyhow!("Column family {} not found", cf_name(col)))
+    }
+}
+
+impl ChainStore for RocksStore {
+    fn get(&self, col: Column, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.db.get_cf(self.handle(col)?, key)?)
+    }
+
+    fn write(&self, batch: StoreBatch) -> Result<()> {
+        let mut write = WriteBatch::default();
+        for op in batch.into_ops() {
+            match op {
+                BatchOp::Put(col, key, value) => write.put_cf(self.handle(col)?, key, value),
+                BatchOp::Delete(col, key) => write.delete_cf(self.handle(col)?, key),
+            }
+        }
+        Ok(self.db.write(write)?)
+    }
+
+    fn iter_from(&self, col: Column, key: &[u8], direction: IterDirection) -> StoreIter<'_> {
+        let handle = match self.handle(col) {
+            Ok(handle) => handle,
+            Err(e) => return Box::new(std::iter::once(Err(e))),
+        };
+        let direction = match direction {
+            IterDirection::Forward => Direction::Forward,
+            IterDirection::Reverse => Direction::Reverse,
+        };
+        Box::new(
+            self.db
+                .iterator_cf(handle, IteratorMode::From(key, direction))
+                .map(|entry| {
+                    entry
+                        .map(|(key, value)| (key.into_vec(), value.into_vec()))
+                        .map_err(Into::into)
+                }),
+        )
+    }
+}
+
+/// Keys of the flat layout that kept everything in the default column family.
+pub mod legacy {
+    pub const LAST_HASH: &[u8] = b"last_hash";
+    pub const CUR_HEIGHT: &[u8] = b"height";
+    pub const CUR_STATE: &[u8] = b"state";
+    pub const BLOCK_PREFIX: &[u8] = b"block_";
+    pub const HEIGHT_PREFIX: &[u8] = b"height_";
+
+    pub fn height_key(height: u64) -> Vec<u8> {
+        format!("height_{:016x}", height).into_bytes()
+    }
+}
+
+/// Moves a database written with the flat `block_<hex>` / `height_<hex>` keys into the
+/// column family layout. Runs in one batch, so it either completes or leaves the old
+/// layout untouched; a migrated database is left alone. The legacy consensus state is
+/// converted by `Consensus::from_legacy_state`, keeping its configuration, and
+/// `BlockChain::with_store` replays the blocks over it.
+pub fn migrate_flat_keys<T, C>(store: &RocksStore) -> Result<bool>
+where
+    T: Transaction + for<'a> Deserialize<'a>,
+    C: Consensus + for<'a> Deserialize<'a>,
+{
+    let db = &store.db;
+    if db.get(legacy::height_key(0))?.is_none() {
+        return Ok(false);
+    }
+    log::info!("Migrating flat keys into column families");
+
+    let headers = store.handle(Column::Headers)?;
+    let bodies = store.handle(Column::Bodies)?;
+    let heights = store.handle(Column::Heights)?;
+    let state = store.handle(Column::State)?;
+
+    let mut batch = WriteBatch::default();
+    let mut blocks = 0usize;
+    for entry in db.iterator(IteratorMode::Start) {
+        let (key, value) = entry?;
+
+        if let Some(hex_hash) = key.strip_prefix(legacy::BLOCK_PREFIX) {
+            let hash = hex::decode(hex_hash)?;
+            let block: Block<T, C> = bincode::deserialize(&value)?;
+            batch.put_cf(headers, &hash, bincode::serialize(&block.header)?);
+            batch.put_cf(bodies, &hash, bincode::serialize(block.transactions())?);
+            blocks += 1;
+        } else if let Some(hex_height) = key.strip_prefix(legacy::HEIGHT_PREFIX) {
+            let height = u64::from_str_radix(std::str::from_utf8(hex_height)?, 16)?;
+            batch.put_cf(heights, DbKeys::height_key(height), &value);
+        } else if &*key == legacy::LAST_HASH {
+            batch.put_cf(state, DbKeys::LAST_HASH, &value);
+        } else if &*key == legacy::CUR_HEIGHT {
+            batch.put_cf(state, DbKeys::CUR_HEIGHT, &value);
+        } else if &*key == legacy::CUR_STATE {
+            let Some(cs) = C::from_legacy_state(&value) else {
+                bail!("Legacy {} state cannot be decoded", C::NAME);
+            };
+            batch.put_cf(state, DbKeys::CUR_STATE, bincode::serialize(&cs)?);
+        } else {
+            continue;
+        }
+        batch.delete(&key);
+    }
+
+    db.write(batch)?;
+    log::info!("Migrated {} blocks", blocks);
+    Ok(true)
+}
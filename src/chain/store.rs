@@ -0,0 +1,178 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Bound,
+    sync::RwLock,
+};
+
+use anyhow::{Result, anyhow};
+
+/// Keyspaces used by `BlockChain`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Column {
+    /// Block hash -> `BlockHeader`.
+    Headers,
+    /// Block hash -> `Transactions`.
+    Bodies,
+    /// Big-endian height -> main chain block hash.
+    Heights,
+    /// Chain tip pointers and consensus state.
+    State,
+    /// Block tree metadata and lookup indexes.
+    Indexes,
+}
+
+impl Column {
+    pub const ALL: [Column; 5] = [
+        Column::Headers,
+        Column::Bodies,
+        Column::Heights,
+        Column::State,
+        Column::Indexes,
+    ];
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IterDirection {
+    Forward,
+    Reverse,
+}
+
+#[derive(Debug, Clone)]
+pub enum BatchOp {
+    Put(Column, Vec<u8>, Vec<u8>),
+    Delete(Column, Vec<u8>),
+}
+
+/// Writes applied atomically and in order by `ChainStore::write`.
+#[derive(Debug, Clone, Default)]
+pub struct StoreBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl StoreBatch {
+    pub fn put(&mut self, col: Column, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) {
+        self.ops.push(BatchOp::Put(
+            col,
+            key.as_ref().to_vec(),
+            value.as_ref().to_vec(),
+        ));
+    }
+
+    pub fn delete(&mut self, col: Column, key: impl AsRef<[u8]>) {
+        self.ops.push(BatchOp::Delete(col, key.as_ref().to_vec()));
+    }
+
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    pub fn into_ops(self) -> Vec<BatchOp> {
+        self.ops
+    }
+}
+
+pub type StoreIter<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a>;
+
+/// Storage operations `BlockChain` is built on.
+pub trait ChainStore {
+    fn get(&self, col: Column, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    fn write(&self, batch: StoreBatch) -> Result<()>;
+
+    /// Entries starting at `key` (inclusive) in key order, or reverse key order.
+    fn iter_from(&self, col: Column, key: &[u8], direction: IterDirection) -> StoreIter<'_>;
+
+    fn prefix_iter(&self, col: Column, prefix: &[u8]) -> StoreIter<'_> {
+        let prefix = prefix.to_vec();
+        Box::new(
+            self.iter_from(col, &prefix, IterDirection::Forward)
+                .take_while(move |entry| {
+                    entry
+                        .as_ref()
+                        .map_or(true, |(key, _)| key.starts_with(&prefix))
+                }),
+        )
+    }
+}
+
+type Tree = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// Volatile store for tests and simulations.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    columns: RwLock<HashMap<Column, Tree>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl ChainStore for MemoryStore {
+    fn get(&self, col: Column, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let columns = self
+            .columns
+            .read()
+            .map_err(|_| anyhow!("Memory store poisoned"))?;
+        Ok(columns.get(&col).and_then(|tree| tree.get(key).cloned()))
+    }
+
+    fn write(&self, batch: StoreBatch) -> Result<()> {
+        let mut columns = self
+            .columns
+            .write()
+            .map_err(|_| anyhow!("Memory store poisoned"))?;
+        for op in batch.into_ops() {
+            match op {
+                BatchOp::Put(col, key, value) => {
+                    columns.entry(col).or_default().insert(key, value);
+                }
+                BatchOp::Delete(col, key) => {
+                    columns.entry(col).or_default().remove(&key);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn iter_from(&self, col: Column, key: &[u8], direction: IterDirection) -> StoreIter<'_> {
+        Box::new(MemoryIter {
+            store: self,
+            col,
+            cursor: Bound::Included(key.to_vec()),
+            direction,
+        })
+    }
+}
+
+/// Re-seeks from the last returned key on every step, so it never holds the lock.
+struct MemoryIter<'a> {
+    store: &'a MemoryStore,
+    col: Column,
+    cursor: Bound<Vec<u8>>,
+    direction: IterDirection,
+}
+
+impl Iterator for MemoryIter<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let columns = match self.store.columns.read() {
+            Ok(columns) => columns,
+            Err(_) => return Some(Err(anyhow!("Memory store poisoned"))),
+        };
+        let tree = columns.get(&self.col)?;
+        let entry = match self.direction {
+            IterDirection::Forward => tree
+                .range::<Vec<u8>, _>((self.cursor.as_ref(), Bound::Unbounded))
+                .next(),
+            IterDirection::Reverse => tree
+                .range::<Vec<u8>, _>((Bound::Unbounded, self.cursor.as_ref()))
+                .next_back(),
+        };
+        let (key, value) = entry?;
+        self.cursor = Bound::Excluded(key.clone());
+        Some(Ok((key.clone(), value.clone())))
+    }
+}
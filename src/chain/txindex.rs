@@ -0,0 +1,151 @@
+use anyhow::{Result, bail};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    block::{Block, BlockHeader, Consensus, Transaction},
+    hash::Hashable,
+};
+
+use super::{
+    BlockChain, DbKeys, Reorg,
+    store::{ChainStore, Column, StoreBatch},
+};
+
+/// Where a main chain transaction is stored.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TxLocation {
+    pub block_hash: Vec<u8>,
+    pub height: u64,
+    /// Index within the block's transactions.
+    pub position: usize,
+}
+
+/// Result of `BlockChain::get_transaction`.
+#[derive(Debug, Clone)]
+pub struct TxLookup<T, D> {
+    pub tx: T,
+    pub header: BlockHeader<D>,
+    pub location: TxLocation,
+    /// Number of main chain blocks from the containing block to the tip, inclusive.
+    pub confirmations: u64,
+}
+
+impl<C: Consensus + for<'a> Deserialize<'a>, S: ChainStore> BlockChain<C, S> {
+    pub fn tx_index_enabled(&self) -> bool {
+        self.tx_index
+    }
+
+    /// Turns the txid index on or off. Enabling indexes the current main chain,
+    /// disabling drops every entry.
+    pub fn set_tx_index<T: Transaction + for<'a> Deserialize<'a>>(
+        &mut self,
+        enabled: bool,
+    ) -> Result<()> {
+        if enabled == self.tx_index {
+            return Ok(());
+        }
+
+        let mut batch = StoreBatch::default();
+        if enabled {
+            for (height, block) in self.iter_blocks::<T>(..)?.enumerate() {
+                let block = block?;
+                self.index_transactions(&mut batch, &block.header.hash(), height as u64, &block)?;
+            }
+        } else {
+            for entry in self
+                .store
+                .prefix_iter(Column::Indexes, &DbKeys::tx_key(&[]))
+            {
+                let (key, _) = entry?;
+                batch.delete(Column::Indexes, key);
+            }
+        }
+        batch.put(Column::State, DbKeys::TX_INDEX, [enabled as u8]);
+        self.store.write(batch)?;
+        self.tx_index = enabled;
+        Ok(())
+    }
+
+    pub fn get_tx_location(&self, txid: &[u8]) -> Result<Option<TxLocation>> {
+        match self.store.get(Column::Indexes, &DbKeys::tx_key(txid))? {
+            Some(raw) => Ok(Some(bincode::deserialize(&raw)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Finds a main chain transaction by txid. Requires the index to be enabled.
+    pub fn get_transaction<T: Transaction + for<'a> Deserialize<'a>>(
+        &self,
+        txid: &[u8],
+    ) -> Result<Option<TxLookup<T, C::Data>>> {
+        if !self.tx_index {
+            bail!("Transaction index is disabled");
+        }
+        let Some(location) = self.get_tx_location(txid)? else {
+            return Ok(None);
+        };
+
+        let block: Block<T, C> = self.get_block_by_hash(&location.block_hash)?;
+        let (header, txs) = block.into_parts();
+        let Some(tx) = txs.0.into_iter().nth(location.position) else {
+            bail!("Indexed transaction missing from block");
+        };
+
+        Ok(Some(TxLookup {
+            tx,
+            header,
+            confirmations: self.get_height()? + 1 - location.height,
+            location,
+        }))
+    }
+
+    pub(super) fn index_transactions<T: Transaction>(
+        &self,
+        batch: &mut StoreBatch,
+        block_hash: &[u8],
+        height: u64,
+        block: &Block<T, C>,
+    ) -> Result<()> {
+        for (position, tx) in block.transactions().0.iter().enumerate() {
+            let location = TxLocation {
+                block_hash: block_hash.to_vec(),
+                height,
+                position,
+            };
+            batch.put(
+                Column::Indexes,
+                DbKeys::tx_key(&tx.hash()),
+                bincode::serialize(&location)?,
+            );
+        }
+        Ok(())
+    }
+
+    /// Moves index entries from the disconnected branch to the connected one.
+    /// Deletions are queued first so transactions present on both branches survive.
+    pub(super) fn reindex_reorg<T: Transaction + for<'a> Deserialize<'a>>(
+        &self,
+        batch: &mut StoreBatch,
+        reorg: &Reorg,
+        fork_height: u64,
+        new_block: &Block<T, C>,
+    ) -> Result<()> {
+        for hash in &reorg.disconnected {
+            let block: Block<T, C> = self.get_block_by_hash(hash)?;
+            for tx in &block.transactions().0 {
+                batch.delete(Column::Indexes, DbKeys::tx_key(&tx.hash()));
+            }
+        }
+
+        for (i, hash) in reorg.connected.iter().enumerate() {
+            let height = fork_height + 1 + i as u64;
+            if *hash == reorg.new_tip {
+                self.index_transactions(batch, hash, height, new_block)?;
+            } else {
+                let block: Block<T, C> = self.get_block_by_hash(hash)?;
+                self.index_transactions(batch, hash, height, &block)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,3 +1,5 @@
+use std::fmt::{self, Display, Formatter};
+
 use num_bigint::BigUint;
 
 pub trait Hashable {
@@ -9,36 +11,87 @@ pub trait Hashable {
     }
 }
 
-const COEFF_MASK:u32 = 0x007f_ffff;
-
-pub fn bits_to_target(bits: u32) -> BigUint {
-    let exp = (bits >> 24) as u8;
-    let coeff = bits & COEFF_MASK;
+const SIGN_BIT: u32 = 0x0080_0000;
+const COEFF_MASK: u32 = 0x007f_ffff;
 
-    assert!(coeff < COEFF_MASK);
+/// Reason a compact encoding does not describe a usable target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompactError {
+    /// The sign bit is set on a nonzero coefficient.
+    Negative,
+    /// The value does not fit in 256 bits.
+    Overflow,
+}
 
-    BigUint::from(coeff) << (8 * (exp - 3))
+impl Display for CompactError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Negative => write!(f, "compact target is negative"),
+            Self::Overflow => write!(f, "compact target overflows 256 bits"),
+        }
+    }
 }
 
-pub fn target_to_bits(target:BigUint) -> u32 {
-    let (exp,coeff) = match target.to_bytes_be().len() {
-        0 => (0,0),
-        len => {
-            let mut size = len;
-            let mut max_coeff = target.clone();
-            while size > 3 {
-                max_coeff >>= 8;
-                size -= 1;
-                if max_coeff <= BigUint::from(COEFF_MASK) {
-                    break;
-                }
-            }
-            
-            // coeff must be < u32
-            let coeff = max_coeff.to_u32_digits().first().copied().unwrap_or(COEFF_MASK);
-
-            (size as u32 + 3, coeff)
+impl std::error::Error for CompactError {}
+
+/// Target in Bitcoin's compact `nBits` form: a size byte followed by a 24 bit
+/// coefficient whose top bit is a sign, so the value is `coeff * 256^(size - 3)`.
+///
+/// Decoding and encoding follow `SetCompact` and `GetCompact` exactly, including
+/// coefficients shifted out entirely for sizes below 3.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CompactTarget(pub u32);
+
+impl CompactTarget {
+    /// Shortest encoding of `target`, losing all but its top 3 significant bytes.
+    pub fn from_target(target: &BigUint) -> Result<Self, CompactError> {
+        let mut size = target.bits().div_ceil(8) as u32;
+        if size > 32 {
+            return Err(CompactError::Overflow);
+        }
+        let mut coeff = if size <= 3 {
+            target.iter_u32_digits().next().unwrap_or(0) << (8 * (3 - size))
+        } else {
+            (target >> (8 * (size - 3))).iter_u32_digits().next().unwrap_or(0)
+        };
+        // A set top bit would read back as the sign, so move it into the next byte.
+        if coeff & SIGN_BIT != 0 {
+            coeff >>= 8;
+            size += 1;
         }
-    };
-    (exp<<24) | coeff
+        Ok(Self((size << 24) | coeff))
+    }
+
+    pub fn to_target(self) -> Result<BigUint, CompactError> {
+        let size = self.0 >> 24;
+        let mut coeff = self.0 & COEFF_MASK;
+        if size <= 3 {
+            coeff >>= 8 * (3 - size);
+        }
+        // Sign and overflow only matter once the shift has left a nonzero coefficient.
+        if coeff == 0 {
+            return Ok(BigUint::ZERO);
+        }
+        if self.0 & SIGN_BIT != 0 {
+            return Err(CompactError::Negative);
+        }
+        if size > 34 || (coeff > 0xff && size > 33) || (coeff > 0xffff && size > 32) {
+            return Err(CompactError::Overflow);
+        }
+        Ok(BigUint::from(coeff) << (8 * size.saturating_sub(3)))
+    }
+}
+
+/// Target encoded by `bits`. Negative or overflowing encodings give zero, a target no
+/// block is expected to meet.
+pub fn bits_to_target(bits: u32) -> BigUint {
+    CompactTarget(bits).to_target().unwrap_or_default()
+}
+
+/// Compact encoding of `target`, capped at the largest 256 bit target.
+pub fn target_to_bits(target: BigUint) -> u32 {
+    let max = (BigUint::from(1u8) << 256) - 1u8;
+    CompactTarget::from_target(&target.min(max))
+        .expect("targets below 2^256 always encode")
+        .0
 }
@@ -1,7 +1,9 @@
 pub mod block;
 pub mod hash;
 pub mod chain;
-pub mod tests;
+pub mod mining;
+#[cfg(test)]
+mod tests;
 fn main() {
     println!("Hello, world!");
 }
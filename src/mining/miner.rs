@@ -0,0 +1,226 @@
+use std::{
+    fmt::{self, Debug, Formatter},
+    num::NonZeroUsize,
+    ops::Range,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::block::{BlockHeader, pow::PoWData, pow_hash::PowHasher};
+
+/// Nonces a worker tries between updates of the shared hash counter.
+const COUNT_BATCH: u64 = 4096;
+
+/// Hashes computed over a stretch of mining time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HashStats {
+    pub hashes: u64,
+    pub elapsed: Duration,
+}
+
+impl HashStats {
+    /// Hashes per second, or 0 if no time has passed.
+    pub fn hashrate(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.hashes as f64 / secs
+        } else {
+            0.0
+        }
+    }
+}
+
+type ProgressFn = dyn Fn(HashStats) + Send + Sync;
+
+/// Shared flag that stops a running `Miner`. Clones observe the same flag.
+#[derive(Debug, Clone, Default)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl CancelHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Clears the flag so the handle can be reused for the next job.
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Proof-of-work search over the nonce space, split across worker threads.
+///
+/// Worker `i` of `n` tries nonces `i, i + n, i + 2n, ...`, so the workers never
+/// overlap. All workers stop once one finds a solution or the cancel handle fires.
+#[derive(Clone)]
+pub struct Miner {
+    threads: usize,
+    cancel: CancelHandle,
+    progress: Option<(Duration, Arc<ProgressFn>)>,
+    /// Totals over every run of this miner and its clones.
+    totals: Arc<Mutex<HashStats>>,
+}
+
+impl Debug for Miner {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("Miner")
+            .field("threads", &self.threads)
+            .field("cancel", &self.cancel)
+            .field("progress_interval", &self.progress.as_ref().map(|(i, _)| i))
+            .field("totals", &self.stats())
+            .finish()
+    }
+}
+
+impl Default for Miner {
+    /// One worker per available CPU.
+    fn default() -> Self {
+        Self::new(thread::available_parallelism().map_or(1, NonZeroUsize::get))
+    }
+}
+
+impl Miner {
+    pub fn new(threads: usize) -> Self {
+        Self {
+            threads: threads.max(1),
+            cancel: CancelHandle::new(),
+            progress: None,
+            totals: Arc::default(),
+        }
+    }
+
+    /// Uses an existing handle, e.g. one shared with the code that receives new blocks.
+    pub fn with_cancel(mut self, cancel: CancelHandle) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Calls `callback` with the stats of the current run at most once per `interval`
+    /// while mining, and once more when a run ends.
+    pub fn with_progress(
+        mut self,
+        interval: Duration,
+        callback: impl Fn(HashStats) + Send + Sync + 'static,
+    ) -> Self {
+        self.progress = Some((interval, Arc::new(callback)));
+        self
+    }
+
+    /// Hashes and mining time summed over all finished runs.
+    pub fn stats(&self) -> HashStats {
+        *self.totals.lock().unwrap()
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    /// Searches the whole nonce space for a proof hash meeting `header.data.bits`.
+    /// Returns `None` if cancelled or no nonce works.
+    pub fn mine(
+        &self,
+        header: BlockHeader<PoWData>,
+        hasher: &(impl PowHasher + Sync),
+    ) -> Option<BlockHeader<PoWData>> {
+        self.mine_range(header, hasher, 0..u64::MAX)
+    }
+
+    /// Like `mine`, trying only the nonces in `nonces`. The rest of the header is left
+    /// as given; changing the timestamp or coinbase is up to the caller.
+    pub fn mine_range(
+        &self,
+        header: BlockHeader<PoWData>,
+        hasher: &(impl PowHasher + Sync),
+        nonces: Range<u64>,
+    ) -> Option<BlockHeader<PoWData>> {
+        let target = header.data.target_bytes();
+        let found = AtomicBool::new(false);
+        let solution = Mutex::new(None);
+        let start = Instant::now();
+        let hashes = AtomicU64::new(0);
+        let last_report = Mutex::new(start);
+
+        thread::scope(|scope| {
+            for worker in 0..self.threads {
+                let mut header = header.clone();
+                let (target, found, solution) = (&target, &found, &solution);
+                let (hashes, last_report, nonces) = (&hashes, &last_report, &nonces);
+                scope.spawn(move || {
+                    let step = self.threads as u64;
+                    let mut next = nonces.start.checked_add(worker as u64);
+                    let mut tried = 0u64;
+                    while let Some(nonce) = next.filter(|nonce| nonces.contains(nonce)) {
+                        if found.load(Ordering::Relaxed) || self.cancel.is_cancelled() {
+                            break;
+                        }
+                        header.data.nonce = nonce;
+                        let hash = hasher.pow_hash(&header.preimage());
+                        tried += 1;
+                        if hash.as_slice() <= target.as_slice() {
+                            if !found.swap(true, Ordering::Relaxed) {
+                                log::debug!("Worker {} found block with nonce {}", worker, nonce);
+                                *solution.lock().unwrap() = Some(header);
+                            }
+                            break;
+                        }
+
+                        next = nonce.checked_add(step);
+                        if tried.is_multiple_of(COUNT_BATCH) {
+                            let total = hashes.fetch_add(COUNT_BATCH, Ordering::Relaxed);
+                            self.report(total + COUNT_BATCH, start, last_report);
+                        }
+                    }
+                    hashes.fetch_add(tried % COUNT_BATCH, Ordering::Relaxed);
+                });
+            }
+        });
+
+        let run = HashStats {
+            hashes: hashes.into_inner(),
+            elapsed: start.elapsed(),
+        };
+        {
+            let mut totals = self.totals.lock().unwrap();
+            totals.hashes += run.hashes;
+            totals.elapsed += run.elapsed;
+        }
+        if let Some((_, callback)) = &self.progress {
+            callback(run);
+        }
+        solution.into_inner().unwrap()
+    }
+
+    /// Reports progress if the interval has passed and no other worker is reporting.
+    fn report(&self, hashes: u64, start: Instant, last_report: &Mutex<Instant>) {
+        let Some((interval, callback)) = &self.progress else {
+            return;
+        };
+        let Ok(mut last) = last_report.try_lock() else {
+            return;
+        };
+        if last.elapsed() >= *interval {
+            *last = Instant::now();
+            callback(HashStats {
+                hashes,
+                elapsed: start.elapsed(),
+            });
+        }
+    }
+}
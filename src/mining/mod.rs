@@ -0,0 +1,4 @@
+pub mod miner;
+pub mod session;
+pub mod stratum;
+pub mod template;
@@ -0,0 +1,127 @@
+//! Long running mining onto a local chain.
+//!
+//! A session mines a fixed template for a bounded number of nonces, then rebuilds it
+//! from the chain tip and the mempool. Every rebuild bumps the extra-nonce carried in
+//! the coinbase, so the merkle root and with it the search space change even when the
+//! transactions and timestamp do not.
+
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::{
+    block::{Transaction, pow::PoW},
+    chain::{BlockChain, ChainUpdate, store::ChainStore},
+};
+
+use super::{
+    miner::Miner,
+    template::{BlockTemplate, Work},
+};
+
+/// Nonces tried per round unless set with `with_round_nonces`.
+const DEFAULT_ROUND_NONCES: u64 = 1 << 24;
+/// Time after which a template is rebuilt unless set with `with_refresh_interval`.
+const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+type CoinbaseFn<T> = dyn Fn(&BlockTemplate<T>, u64) -> T + Send;
+type MempoolFn<T> = dyn FnMut() -> Vec<T> + Send;
+
+pub struct MiningSession<T> {
+    miner: Miner,
+    coinbase: Box<CoinbaseFn<T>>,
+    mempool: Box<MempoolFn<T>>,
+    round_nonces: u64,
+    refresh_interval: Duration,
+    extra_nonce: u64,
+}
+
+impl<T: Transaction + Clone + for<'a> Deserialize<'a>> MiningSession<T> {
+    /// `coinbase` builds the reward transaction for a template and extra-nonce;
+    /// `mempool` returns the transactions to include, and is asked again on every
+    /// rebuild.
+    pub fn new(
+        miner: Miner,
+        coinbase: impl Fn(&BlockTemplate<T>, u64) -> T + Send + 'static,
+        mempool: impl FnMut() -> Vec<T> + Send + 'static,
+    ) -> Self {
+        Self {
+            miner,
+            coinbase: Box::new(coinbase),
+            mempool: Box::new(mempool),
+            round_nonces: DEFAULT_ROUND_NONCES,
+            refresh_interval: DEFAULT_REFRESH_INTERVAL,
+            extra_nonce: 0,
+        }
+    }
+
+    pub fn with_round_nonces(mut self, nonces: u64) -> Self {
+        self.round_nonces = nonces.max(1);
+        self
+    }
+
+    /// Rebuilds the template after this long even if its nonces are not used up.
+    pub fn with_refresh_interval(mut self, interval: Duration) -> Self {
+        self.refresh_interval = interval;
+        self
+    }
+
+    pub fn miner(&self) -> &Miner {
+        &self.miner
+    }
+
+    /// Extra-nonce the next template will carry.
+    pub fn extra_nonce(&self) -> u64 {
+        self.extra_nonce
+    }
+
+    /// Mines until a block is added to `chain`. Returns `None` if the miner is
+    /// cancelled first.
+    pub fn mine_block<S: ChainStore>(
+        &mut self,
+        chain: &mut BlockChain<PoW, S>,
+    ) -> Result<Option<ChainUpdate>> {
+        let cancel = self.miner.cancel_handle();
+        while !cancel.is_cancelled() {
+            let template = self.next_template(chain)?;
+            let deadline = Instant::now() + self.refresh_interval;
+            let mut start = 0;
+            while start < u64::MAX && !cancel.is_cancelled() {
+                let end = start.saturating_add(self.round_nonces);
+                let header = self.miner.mine_range(
+                    template.initial_header(),
+                    &template.algorithm,
+                    start..end,
+                );
+                if let Some(header) = header {
+                    return chain.submit_work(template, Work::from(&header)).map(Some);
+                }
+                if Instant::now() >= deadline {
+                    break;
+                }
+                start = end;
+            }
+        }
+        Ok(None)
+    }
+
+    /// Fresh template from the tip and the mempool, with the next extra-nonce.
+    fn next_template<S: ChainStore>(
+        &mut self,
+        chain: &BlockChain<PoW, S>,
+    ) -> Result<BlockTemplate<T>> {
+        let extra_nonce = self.extra_nonce;
+        self.extra_nonce = extra_nonce.wrapping_add(1);
+        let coinbase = &self.coinbase;
+        let template = chain
+            .block_template_with(|template| coinbase(template, extra_nonce), (self.mempool)())?;
+        log::debug!(
+            "Mining height {} with extra-nonce {} and {} transactions",
+            template.height,
+            extra_nonce,
+            template.transactions.len()
+        );
+        Ok(template)
+    }
+}
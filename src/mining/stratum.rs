@@ -0,0 +1,347 @@
+//! Line-delimited JSON protocol for sharing mining jobs, loosely modelled on stratum.
+//!
+//! Every message is a single JSON object on its own line. Clients send `Request`s and
+//! get one `Response` back for each; subscribed clients are also pushed a
+//! `Response::Job` whenever the job changes.
+
+use std::{
+    collections::HashSet,
+    io::{BufRead, BufReader, Read, Write},
+    marker::PhantomData,
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Result, anyhow, bail};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    block::{
+        Transaction,
+        pow::{PoW, PoWData},
+    },
+    chain::{BlockChain, store::ChainStore},
+};
+
+use super::template::{BlockTemplate, Work};
+
+/// Longest request line a client may send, newline excluded.
+pub const MAX_LINE: usize = 64 * 1024;
+/// How long a write to a miner may block before the connection is dropped.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Write half of a miner connection; the lock keeps replies and pushed jobs whole.
+type Conn = Arc<Mutex<TcpStream>>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+pub enum Request {
+    /// Returns the current job and pushes every later one.
+    Subscribe,
+    GetJob,
+    Submit {
+        job_id: u64,
+        work: Work,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Job<T> {
+    pub job_id: u64,
+    /// Big-endian 32 byte target a share must meet; never harder than the block target.
+    pub share_target: Vec<u8>,
+    pub template: BlockTemplate<T>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response<T> {
+    Job(Job<T>),
+    /// A share was accepted; `block` is set if it also met the block target.
+    Accepted {
+        job_id: u64,
+        block: bool,
+    },
+    Error {
+        message: String,
+    },
+}
+
+/// Serves jobs for a `BlockChain<PoW>` to any number of miner connections.
+///
+/// Jobs are built from transactions handed to `add_transactions` and rebuilt whenever
+/// a submitted share completes a block.
+pub struct StratumServer<T, S: ChainStore> {
+    shared: Arc<Shared<T, S>>,
+}
+
+struct Shared<T, S: ChainStore> {
+    chain: Mutex<BlockChain<PoW, S>>,
+    share_bits: u32,
+    board: Mutex<JobBoard<T>>,
+}
+
+struct JobBoard<T> {
+    next_id: u64,
+    job: Option<Job<T>>,
+    pending: Vec<T>,
+    subscribers: Vec<Conn>,
+    /// Share hashes seen for the current job.
+    seen: HashSet<[u8; 32]>,
+    shares: u64,
+}
+
+impl<T, S: ChainStore> Clone for StratumServer<T, S> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T, S> StratumServer<T, S>
+where
+    T: Transaction + Clone + Serialize + for<'a> Deserialize<'a> + Send + 'static,
+    S: ChainStore + Send + 'static,
+{
+    pub fn new(chain: BlockChain<PoW, S>, share_bits: u32) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                chain: Mutex::new(chain),
+                share_bits,
+                board: Mutex::new(JobBoard {
+                    next_id: 0,
+                    job: None,
+                    pending: Vec::new(),
+                    subscribers: Vec::new(),
+                    seen: HashSet::new(),
+                    shares: 0,
+                }),
+            }),
+        }
+    }
+
+    pub fn chain(&self) -> MutexGuard<'_, BlockChain<PoW, S>> {
+        lock(&self.shared.chain)
+    }
+
+    pub fn current_job(&self) -> Option<Job<T>> {
+        lock(&self.shared.board).job.clone()
+    }
+
+    /// Number of shares accepted so far, including those that completed a block.
+    pub fn shares(&self) -> u64 {
+        lock(&self.shared.board).shares
+    }
+
+    /// Queues transactions for the next job and pushes it to subscribers.
+    pub fn add_transactions(&self, txs: Vec<T>) {
+        let mut board = lock(&self.shared.board);
+        board.pending.extend(txs);
+        self.refresh(&mut board);
+        drop(board);
+        self.broadcast();
+    }
+
+    /// Accepts connections until the listener fails, one thread per client.
+    pub fn serve(&self, listener: TcpListener) -> Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let server = self.clone();
+            thread::spawn(move || {
+                let peer = stream.peer_addr().ok();
+                if let Err(e) = server.handle_client(stream) {
+                    log::warn!("Miner connection {:?} closed: {}", peer, e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn handle_client(&self, stream: TcpStream) -> Result<()> {
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        let writer: Conn = Arc::new(Mutex::new(stream.try_clone()?));
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            // One byte past the cap tells an overlong line apart from one that fits.
+            if (&mut reader)
+                .take(MAX_LINE as u64 + 1)
+                .read_line(&mut line)?
+                == 0
+            {
+                break;
+            }
+            if line.len() > MAX_LINE && !line.ends_with('\n') {
+                bail!("Request line longer than {} bytes", MAX_LINE);
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+            let mut found_block = false;
+            let response = match serde_json::from_str(&line) {
+                Ok(Request::Subscribe) => {
+                    lock(&self.shared.board).subscribers.push(writer.clone());
+                    self.job_response()
+                }
+                Ok(Request::GetJob) => self.job_response(),
+                Ok(Request::Submit { job_id, work }) => match self.submit(job_id, work) {
+                    Ok(block) => {
+                        found_block = block;
+                        Response::Accepted { job_id, block }
+                    }
+                    Err(e) => error_response(e),
+                },
+                Err(e) => error_response(anyhow!("Malformed request: {}", e)),
+            };
+            write_line(&mut lock(&writer), &response)?;
+            if found_block {
+                self.broadcast();
+            }
+        }
+        Ok(())
+    }
+
+    fn job_response(&self) -> Response<T> {
+        match self.current_job() {
+            Some(job) => Response::Job(job),
+            None => error_response(anyhow!("No job available")),
+        }
+    }
+
+    /// Checks a share against the current job. Returns whether it completed a block.
+    fn submit(&self, job_id: u64, work: Work) -> Result<bool> {
+        let mut board = lock(&self.shared.board);
+        let Some(job) = board.job.as_ref().filter(|job| job.job_id == job_id) else {
+            bail!("Stale job {}", job_id);
+        };
+        let template = job.template.clone();
+        let hash = template.pow_hash(work);
+        if hash.as_slice() > job.share_target.as_slice() {
+            bail!("Share does not meet the share target");
+        }
+        if !board.seen.insert(hash) {
+            bail!("Duplicate share");
+        }
+        if hash.as_slice() > template.target.as_slice() {
+            board.shares += 1;
+            return Ok(false);
+        }
+
+        let included: HashSet<[u8; 32]> =
+            template.transactions.iter().map(|tx| tx.hash()).collect();
+        lock(&self.shared.chain).submit_work(template, work)?;
+        board.shares += 1;
+        log::info!("Block found through job {}", job_id);
+        board.pending.retain(|tx| !included.contains(&tx.hash()));
+        self.refresh(&mut board);
+        Ok(true)
+    }
+
+    fn refresh(&self, board: &mut JobBoard<T>) {
+        let template = lock(&self.shared.chain).block_template(None, board.pending.clone());
+        board.seen.clear();
+        board.job = match template {
+            Ok(template) => {
+                board.next_id += 1;
+                let share_target = PoWData {
+                    bits: self.shared.share_bits,
+                    nonce: 0,
+                }
+                .target_bytes()
+                .to_vec();
+                // Never harder than the block target, so every block solution is a share.
+                let share_target = share_target.max(template.target.clone());
+                Some(Job {
+                    job_id: board.next_id,
+                    share_target,
+                    template,
+                })
+            }
+            Err(e) => {
+                log::debug!("No job: {}", e);
+                None
+            }
+        };
+    }
+
+    /// Pushes the current job to subscribers, dropping those that disconnected or
+    /// stalled past the write timeout.
+    ///
+    /// The board is not held while writing, so a slow miner cannot block submissions.
+    fn broadcast(&self) {
+        let (job, subscribers) = {
+            let board = lock(&self.shared.board);
+            (board.job.clone(), board.subscribers.clone())
+        };
+        let Some(job) = job else {
+            return;
+        };
+        let message = Response::Job(job);
+        let failed: Vec<Conn> = subscribers
+            .into_iter()
+            .filter(|conn| write_line(&mut lock(conn), &message).is_err())
+            .collect();
+        if !failed.is_empty() {
+            lock(&self.shared.board)
+                .subscribers
+                .retain(|conn| !failed.iter().any(|f| Arc::ptr_eq(f, conn)));
+        }
+    }
+}
+
+/// Blocking client for `StratumServer`.
+pub struct StratumClient<T> {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    _tx: PhantomData<T>,
+}
+
+impl<T: for<'a> Deserialize<'a>> StratumClient<T> {
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(Self {
+            writer: stream.try_clone()?,
+            reader: BufReader::new(stream),
+            _tx: PhantomData,
+        })
+    }
+
+    pub fn send(&mut self, request: &Request) -> Result<()> {
+        write_line(&mut self.writer, request)
+    }
+
+    /// Waits for the next message, either a reply or a pushed job.
+    pub fn read(&mut self) -> Result<Response<T>> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            bail!("Connection closed by server");
+        }
+        Ok(serde_json::from_str(&line)?)
+    }
+
+    pub fn request(&mut self, request: &Request) -> Result<Response<T>> {
+        self.send(request)?;
+        self.read()
+    }
+}
+
+fn write_line(stream: &mut TcpStream, message: &impl Serialize) -> Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    stream.write_all(&line)?;
+    Ok(())
+}
+
+fn error_response<T>(e: anyhow::Error) -> Response<T> {
+    Response::Error {
+        message: e.to_string(),
+    }
+}
+
+fn lock<X>(mutex: &Mutex<X>) -> MutexGuard<'_, X> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
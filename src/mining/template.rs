@@ -0,0 +1,98 @@
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+
+use crate::block::{
+    Block, BlockHeader, Transaction, Transactions,
+    pow::{PoW, PoWData},
+    pow_hash::{PowAlgorithm, PowHasher},
+};
+
+/// Everything an external miner needs to search for a block on top of the tip.
+///
+/// Produced by `BlockChain::block_template`; a solution comes back as `Work` and is
+/// turned into a block with `into_block`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockTemplate<T> {
+    pub height: u64,
+    pub prev_hash: Vec<u8>,
+    /// Root over `coinbase` followed by `transactions`.
+    pub merkle_root: Vec<u8>,
+    pub timestamp: i64,
+    pub bits: u32,
+    /// Big-endian 32 byte target the proof hash must not exceed.
+    pub target: Vec<u8>,
+    pub algorithm: PowAlgorithm,
+    /// Most a coinbase may claim: the subsidy plus fees of `transactions`.
+    pub reward: u64,
+    /// Slot for the block reward transaction, placed first in the block.
+    pub coinbase: Option<T>,
+    pub transactions: Vec<T>,
+}
+
+/// The header fields a miner varies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Work {
+    pub timestamp: i64,
+    pub nonce: u64,
+}
+
+impl From<&BlockHeader<PoWData>> for Work {
+    fn from(header: &BlockHeader<PoWData>) -> Self {
+        Self {
+            timestamp: header.timestamp,
+            nonce: header.data.nonce,
+        }
+    }
+}
+
+impl<T: Transaction + Clone> BlockTemplate<T> {
+    /// Header with the template's fixed fields and `work` filled in.
+    pub fn header(&self, work: Work) -> BlockHeader<PoWData> {
+        BlockHeader {
+            prev_hash: self.prev_hash.clone(),
+            merkle_root: self.merkle_root.clone(),
+            timestamp: work.timestamp,
+            data: PoWData {
+                bits: self.bits,
+                nonce: work.nonce,
+            },
+        }
+    }
+
+    /// Header to start mining from, with the template timestamp and nonce 0.
+    pub fn initial_header(&self) -> BlockHeader<PoWData> {
+        self.header(Work {
+            timestamp: self.timestamp,
+            nonce: 0,
+        })
+    }
+
+    /// Proof hash of the header completed with `work`.
+    pub fn pow_hash(&self, work: Work) -> [u8; 32] {
+        self.algorithm.pow_hash(&self.header(work).preimage())
+    }
+
+    /// Fills the coinbase slot, updating the merkle root.
+    pub fn with_coinbase(mut self, coinbase: T) -> Result<Self> {
+        self.coinbase = Some(coinbase);
+        self.merkle_root = self
+            .block_transactions()
+            .merkle_root()
+            .ok_or_else(|| anyhow!("No merkle root found!"))?;
+        Ok(self)
+    }
+
+    pub fn block_transactions(&self) -> Transactions<T> {
+        Transactions(
+            self.coinbase
+                .iter()
+                .chain(&self.transactions)
+                .cloned()
+                .collect(),
+        )
+    }
+
+    pub fn into_block(self, work: Work) -> Block<T, PoW> {
+        Block::from_parts(self.header(work), self.block_transactions())
+    }
+}